@@ -0,0 +1,122 @@
+use std::collections::HashMap;
+
+use worker::*;
+
+use crate::agent;
+use crate::auth::{authorize, Scope};
+use crate::error::{ApiResult, ErrorResponse};
+use crate::request::param;
+use crate::state::{AppStateKvStore, Entry, Sdp};
+use signal_protocol::{AgentSummary, PurgeResponse, SdpSummary};
+
+pub async fn handle_list_agents(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    list_agents(req, ctx).await.or_else(|e| e.to_response())
+}
+
+pub async fn handle_list_sdps(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    list_sdps(req, ctx).await.or_else(|e| e.to_response())
+}
+
+pub async fn handle_query_sweep(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    query_sweep(req, ctx).await.or_else(|e| e.to_response())
+}
+
+pub async fn handle_expire_agent(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    expire_agent(req, ctx).await.or_else(|e| e.to_response())
+}
+
+pub async fn handle_purge_namespace(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    purge_namespace(req, ctx).await.or_else(|e| e.to_response())
+}
+
+async fn list_agents(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Admin)?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let agents = kv
+        .list_agents()
+        .await?
+        .into_iter()
+        .map(|entry| AgentSummary {
+            name: entry.value.name,
+            uuid: entry.value.uuid,
+            os: entry.value.os,
+            version: entry.value.version,
+            last_seen: entry.value.last_seen,
+            expires_at: entry.expires_at,
+        })
+        .collect::<Vec<_>>();
+
+    Ok(Response::from_json(&agents)?)
+}
+
+async fn list_sdps(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Admin)?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let now = chrono::Utc::now().timestamp();
+    let mut sdps = vec![];
+    for (uuid, entry) in kv.list_client_sdps().await? {
+        sdps.push(summarize_sdp("client", uuid, entry, now));
+    }
+    for (uuid, entry) in kv.list_agent_sdps().await? {
+        sdps.push(summarize_sdp("agent", uuid, entry, now));
+    }
+
+    Ok(Response::from_json(&sdps)?)
+}
+
+async fn query_sweep(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Admin)?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    match kv.query_sweep_report().await? {
+        None => Err(ErrorResponse::not_found("no sweep has run yet")),
+        Some(report) => Ok(Response::from_json(&report)?),
+    }
+}
+
+async fn expire_agent(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Admin)?;
+    let name = param(&ctx, "name");
+    let uuid = param(&ctx, "uuid");
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    if !kv.delete_agent(&name, &uuid).await? {
+        return Err(ErrorResponse::not_found(format!(
+            "unknown agent {name}, uuid: {uuid}"
+        )));
+    }
+    agent::notify_offline(&ctx, &token.namespace, &name, &uuid);
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
+
+async fn purge_namespace(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Admin)?;
+    let namespace = param(&ctx, "namespace");
+    // an admin token only administers its own tenant
+    if namespace != token.namespace {
+        return Err(ErrorResponse::forbidden(format!(
+            "token is not allowed to purge namespace {namespace}"
+        )));
+    }
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &namespace)?;
+    let deleted = kv.purge().await?;
+
+    Ok(Response::from_json(&PurgeResponse { namespace, deleted })?)
+}
+
+fn summarize_sdp(kind: &str, uuid: String, entry: Entry<Sdp>, now: i64) -> SdpSummary {
+    SdpSummary {
+        kind: kind.to_string(),
+        uuid,
+        session: entry.value.session,
+        is_udp: entry.value.is_udp,
+        port: entry.value.port,
+        size: entry.value.sdp.len(),
+        age: now - entry.updated_at,
+        expires_at: entry.expires_at,
+    }
+}
@@ -2,41 +2,140 @@ use std::collections::HashMap;
 
 use worker::*;
 
-use crate::state::{AbstractKvStore, Agent, AppStateKvStore};
+use crate::auth::{authorize, Scope};
+use crate::error::{ApiResult, ErrorResponse};
+use crate::request::{param, read_json};
+use crate::state::{Agent, AppStateKvStore};
+use crate::webhook::{self, EventType, WebhookEvent};
 
-pub async fn handle_publish_agent(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let mut name = String::new();
-    if let Some(n) = ctx.param("name") {
-        name = n.to_string();
-    }
+pub async fn handle_publish_agent(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    publish_agent(req, ctx).await.or_else(|e| e.to_response())
+}
+
+pub async fn handle_query_agent(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    query_agent(req, ctx).await.or_else(|e| e.to_response())
+}
+
+pub async fn handle_delete_agent(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    delete_agent(req, ctx).await.or_else(|e| e.to_response())
+}
+
+pub async fn handle_publish_agent_v2(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    publish_agent_v2(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_delete_agent_v2(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    delete_agent_v2(req, ctx).await.or_else(|e| e.to_response())
+}
+
+async fn publish_agent(mut req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let name = param(&ctx, "name");
+    let agent = read_json::<Agent, _>(&mut req, &ctx).await?;
+    token.check_uuid(&agent.uuid)?;
+
+    save_agent(&ctx, &token.namespace, &name, agent).await
+}
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    kv.insert_or_update_agent(&name, req.json::<Agent>().await?)
-        .await;
+async fn query_agent(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Client)?;
+    let name = param(&ctx, "name");
 
-    Response::from_json(&HashMap::<String, String>::new())
+    // a list, so no agents of that name is an empty list rather than a 404
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let agents = kv.query_agent(&name).await?;
+
+    Ok(Response::from_json(&agents)?)
+}
+
+async fn delete_agent(mut req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let name = param(&ctx, "name");
+    let agent = read_json::<Agent, _>(&mut req, &ctx).await?;
+    token.check_uuid(&agent.uuid)?;
+
+    remove_agent(&ctx, &token.namespace, &name, &agent.uuid).await
 }
 
-pub async fn handle_query_agent(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let mut name = String::new();
-    if let Some(n) = ctx.param("name") {
-        name = n.to_string();
+async fn publish_agent_v2(mut req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let name = param(&ctx, "name");
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
+    let agent = read_json::<Agent, _>(&mut req, &ctx).await?;
+    if agent.uuid != uuid {
+        return Err(ErrorResponse::bad_request(format!(
+            "agent uuid {} does not match path uuid {uuid}",
+            agent.uuid
+        )));
     }
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    let agents = kv.query_agent(&name).await;
+    save_agent(&ctx, &token.namespace, &name, agent).await
+}
+
+async fn delete_agent_v2(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let name = param(&ctx, "name");
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
 
-    Response::from_json(&agents)
+    remove_agent(&ctx, &token.namespace, &name, &uuid).await
 }
 
-pub async fn handle_delete_agent(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let mut name = String::new();
-    if let Some(n) = ctx.param("name") {
-        name = n.to_string();
+async fn save_agent(
+    ctx: &RouteContext<Context>,
+    namespace: &str,
+    name: &str,
+    mut agent: Agent,
+) -> ApiResult<Response> {
+    agent.last_seen = chrono::Utc::now().timestamp();
+
+    let mut kv = AppStateKvStore::from_ctx(ctx, namespace)?;
+    let (is_new, expired) = kv.insert_or_update_agent(name, agent.clone()).await?;
+    // agents of the name that missed their heartbeats, pruned before the sweep saw them
+    for expired in &expired {
+        webhook::notify_later(
+            ctx,
+            WebhookEvent::new(EventType::AgentOffline, namespace, expired),
+        );
     }
+    if is_new {
+        webhook::notify_later(
+            ctx,
+            WebhookEvent::new(EventType::AgentOnline, namespace, &agent),
+        );
+    }
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    kv.delete_agent(&name, req.json::<Agent>().await?).await;
+async fn remove_agent(
+    ctx: &RouteContext<Context>,
+    namespace: &str,
+    name: &str,
+    uuid: &str,
+) -> ApiResult<Response> {
+    let mut kv = AppStateKvStore::from_ctx(ctx, namespace)?;
+    if !kv.delete_agent(name, uuid).await? {
+        return Err(ErrorResponse::not_found(format!(
+            "unknown agent {name}, uuid: {uuid}"
+        )));
+    }
+    notify_offline(ctx, namespace, name, uuid);
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
 
-    Response::from_json(&HashMap::<String, String>::new())
+pub fn notify_offline(ctx: &RouteContext<Context>, namespace: &str, name: &str, uuid: &str) {
+    let agent = Agent {
+        uuid: uuid.to_string(),
+        name: name.to_string(),
+        ..Default::default()
+    };
+    webhook::notify_later(
+        ctx,
+        WebhookEvent::new(EventType::AgentOffline, namespace, &agent),
+    );
 }
@@ -0,0 +1,137 @@
+use serde::{Deserialize, Serialize};
+use worker::{console_warn, Request, RouteContext};
+
+use crate::error::{ApiResult, ErrorResponse};
+use crate::state::{check_key_segment, AbstractKvStore, AppStateKvStore};
+pub use signal_protocol::Scope;
+
+static AUTH_TOKENS_KEY: &str = "AUTH_TOKENS";
+static AUTH_DISABLED_KEY: &str = "AUTH_DISABLED";
+static AUTHORIZATION_HEADER: &str = "Authorization";
+static BEARER_PREFIX: &str = "Bearer ";
+
+// One entry of the AUTH_TOKENS secret, a json array of these
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct TokenEntry {
+    pub token: String,
+    pub scopes: Vec<Scope>,
+    // agent uuids this token may act as, empty means any
+    #[serde(default)]
+    pub uuids: Vec<String>,
+    // tenant whose agents and sdps this token sees
+    #[serde(default = "default_namespace")]
+    pub namespace: String,
+}
+
+fn default_namespace() -> String {
+    <AppStateKvStore>::get_default_namespace().to_string()
+}
+
+impl TokenEntry {
+    pub fn has_scope(&self, scope: Scope) -> bool {
+        self.scopes.contains(&Scope::Admin) || self.scopes.contains(&scope)
+    }
+
+    pub fn check_uuid(&self, uuid: &str) -> ApiResult<()> {
+        if self.uuids.is_empty() || self.uuids.iter().any(|u| u == uuid) {
+            Ok(())
+        } else {
+            Err(ErrorResponse::forbidden(format!(
+                "token is not allowed to act as agent {uuid}"
+            )))
+        }
+    }
+}
+
+// Resolve the bearer token of a request and check it grants scope
+// without an AUTH_TOKENS secret every request fails, unless AUTH_DISABLED is true
+pub fn authorize<D>(req: &Request, ctx: &RouteContext<D>, scope: Scope) -> ApiResult<TokenEntry> {
+    authorize_token(ctx, bearer_token(req), scope)
+}
+
+// Same as authorize for a token that did not arrive in the Authorization header
+pub fn authorize_token<D>(
+    ctx: &RouteContext<D>,
+    token: Option<String>,
+    scope: Scope,
+) -> ApiResult<TokenEntry> {
+    authorize_with(ctx, token, scope, |entry, token| {
+        constant_time_eq(entry.token.as_bytes(), token.as_bytes())
+    })
+}
+
+// Same as authorize_token for a credential derived from a token, e.g. a dashboard session
+// matches tells whether the credential was derived from an entry
+pub fn authorize_with<D>(
+    ctx: &RouteContext<D>,
+    token: Option<String>,
+    scope: Scope,
+    matches: impl Fn(&TokenEntry, &str) -> bool,
+) -> ApiResult<TokenEntry> {
+    let entries = match ctx.secret(AUTH_TOKENS_KEY) {
+        Err(_) if auth_disabled(ctx) => {
+            console_warn!("{AUTH_DISABLED_KEY} is set, accepting the request without a token");
+            return Ok(TokenEntry {
+                scopes: vec![Scope::Admin],
+                namespace: default_namespace(),
+                ..Default::default()
+            });
+        }
+        Err(_) => {
+            return Err(ErrorResponse::config_error(format!(
+                "{AUTH_TOKENS_KEY} is not set, set {AUTH_DISABLED_KEY}=true to run without auth"
+            )))
+        }
+        Ok(secret) => {
+            serde_json::from_str::<Vec<TokenEntry>>(&secret.to_string()).map_err(|e| {
+                ErrorResponse::config_error(format!("malformed {AUTH_TOKENS_KEY}, e: {e}"))
+            })?
+        }
+    };
+
+    if let Some(e) = entries
+        .iter()
+        .find_map(|e| check_key_segment("namespace", &e.namespace).err())
+    {
+        return Err(ErrorResponse::config_error(format!(
+            "malformed {AUTH_TOKENS_KEY}, e: {}",
+            e.message
+        )));
+    }
+
+    let token = token.ok_or_else(|| ErrorResponse::unauthorized("missing bearer token"))?;
+
+    let entry = entries
+        .into_iter()
+        .find(|e| matches(e, &token))
+        .ok_or_else(|| ErrorResponse::unauthorized("invalid bearer token"))?;
+    if !entry.has_scope(scope) {
+        return Err(ErrorResponse::forbidden(format!(
+            "token lacks the {scope:?} scope"
+        )));
+    }
+
+    Ok(entry)
+}
+
+// Open mode has to be asked for explicitly, a forgotten secret must not expose the worker
+fn auth_disabled<D>(ctx: &RouteContext<D>) -> bool {
+    ctx.var(AUTH_DISABLED_KEY)
+        .map(|v| v.to_string() == "true")
+        .unwrap_or(false)
+}
+
+// Bearer token of a request, if any
+pub fn bearer_token(req: &Request) -> Option<String> {
+    req.headers()
+        .get(AUTHORIZATION_HEADER)
+        .ok()
+        .flatten()
+        .and_then(|v| v.strip_prefix(BEARER_PREFIX).map(|t| t.trim().to_string()))
+        .filter(|t| !t.is_empty())
+}
+
+// Compare without an early exit so response timing does not leak token prefixes
+pub fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0u8, |acc, (x, y)| acc | (x ^ y)) == 0
+}
@@ -0,0 +1,167 @@
+use std::collections::HashMap;
+
+use worker::*;
+
+use crate::auth::{authorize, Scope};
+use crate::error::{ApiResult, ErrorResponse};
+use crate::request::{param, read_json};
+use crate::state::{AppStateKvStore, Candidate};
+
+pub async fn handle_publish_client_candidate(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    publish_client_candidate(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_query_client_candidates(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    query_client_candidates(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_publish_agent_candidate(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    publish_agent_candidate(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_query_agent_candidates(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    query_agent_candidates(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+async fn publish_client_candidate(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Client)?;
+    let uuid = param(&ctx, "uuid");
+    let candidate = read_candidate(&mut req, &ctx).await?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    kv.insert_or_update_client_candidate(&uuid, candidate)
+        .await?;
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
+
+async fn query_client_candidates(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let candidates = kv
+        .query_client_candidates(&uuid, &param(&ctx, "session"))
+        .await?;
+    Ok(Response::from_json(&candidates)?)
+}
+
+async fn publish_agent_candidate(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
+    let candidate = read_candidate(&mut req, &ctx).await?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    kv.insert_or_update_agent_candidate(&uuid, candidate)
+        .await?;
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
+
+async fn query_agent_candidates(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Client)?;
+    let uuid = param(&ctx, "uuid");
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let candidates = kv
+        .query_agent_candidates(&uuid, &param(&ctx, "session"))
+        .await?;
+    Ok(Response::from_json(&candidates)?)
+}
+
+// Read a candidate body, its session and index must match the path
+async fn read_candidate(req: &mut Request, ctx: &RouteContext<Context>) -> ApiResult<Candidate> {
+    let candidate = read_json::<Candidate, _>(req, ctx).await?;
+    check_candidate(candidate, param(ctx, "session"), &param(ctx, "index"))
+}
+
+// A candidate without a session takes the one of the path
+pub fn check_candidate(
+    mut candidate: Candidate,
+    session: String,
+    index: &str,
+) -> ApiResult<Candidate> {
+    let index = index
+        .parse::<u32>()
+        .map_err(|e| ErrorResponse::bad_request(format!("invalid candidate index, e: {e}")))?;
+
+    if candidate.session.is_empty() {
+        candidate.session = session;
+    } else if candidate.session != session {
+        return Err(ErrorResponse::bad_request(format!(
+            "candidate session {} does not match path session {session}",
+            candidate.session
+        )));
+    }
+    if candidate.index != index {
+        return Err(ErrorResponse::bad_request(format!(
+            "candidate index {} does not match path index {index}",
+            candidate.index
+        )));
+    }
+    Ok(candidate)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorCode;
+
+    fn candidate(session: &str, index: u32) -> Candidate {
+        Candidate {
+            session: session.to_string(),
+            index,
+            ..Default::default()
+        }
+    }
+
+    #[test]
+    fn check_candidate_accepts_the_path_session_and_index() {
+        let c = check_candidate(candidate("s1", 3), "s1".to_string(), "3").unwrap();
+        assert_eq!(c, candidate("s1", 3));
+        // a body may leave the session to the path
+        let c = check_candidate(candidate("", 3), "s1".to_string(), "3").unwrap();
+        assert_eq!(c.session, "s1");
+    }
+
+    #[test]
+    fn check_candidate_rejects_mismatches() {
+        for (c, session, index) in [
+            (candidate("s2", 3), "s1", "3"),
+            (candidate("s1", 4), "s1", "3"),
+            (candidate("s1", 3), "s1", "three"),
+            (candidate("s1", 3), "s1", "-3"),
+        ] {
+            let e = check_candidate(c, session.to_string(), index).unwrap_err();
+            assert_eq!(e.code, ErrorCode::BadRequest);
+        }
+    }
+}
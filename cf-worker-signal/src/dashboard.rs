@@ -0,0 +1,299 @@
+use std::collections::BTreeMap;
+
+use hmac::{Hmac, Mac};
+use sha2::Sha256;
+use worker::*;
+
+use crate::auth::{authorize_token, authorize_with, bearer_token, constant_time_eq, Scope};
+use crate::error::ApiResult;
+use crate::state::{Agent, AppStateKvStore, Entry, Sdp};
+use signal_protocol::routes;
+
+static TOKEN_QUERY_KEY: &str = "token";
+static SESSION_COOKIE: &str = "dashboard_session";
+static SESSION_SECS: i64 = 3600;
+static REFRESH_SECS: u32 = 30;
+
+pub async fn handle_dashboard(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    dashboard(req, ctx).await.or_else(|e| e.to_response())
+}
+
+// Browsers cannot set the Authorization header on a plain link, so ?token= works too
+// it is traded for a session cookie right away, so the token does not stay in the address
+// bar, the refreshes and the referer of the page
+async fn dashboard(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let now = chrono::Utc::now().timestamp();
+    let token = if let Some(token) = bearer_token(&req) {
+        authorize_token(&ctx, Some(token), Scope::Admin)?
+    } else if let Some(token) = query_token(&req) {
+        let token = authorize_token(&ctx, Some(token), Scope::Admin)?;
+        return Ok(open_session(&req, &token.token, now)?);
+    } else {
+        let session = req
+            .headers()
+            .get("Cookie")?
+            .and_then(|header| cookie(&header, SESSION_COOKIE));
+        authorize_with(&ctx, session, Scope::Admin, |entry, session| {
+            session_matches(&entry.token, session, now)
+        })?
+    };
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let agents = kv.list_agents().await?;
+    let requests = kv.list_client_sdps().await?;
+    let html = render(&token.namespace, &agents, &requests, now);
+
+    let mut response = Response::from_html(html)?;
+    response.headers_mut().set("Cache-Control", "no-store")?;
+    response
+        .headers_mut()
+        .set("Referrer-Policy", "no-referrer")?;
+    Ok(response)
+}
+
+fn query_token(req: &Request) -> Option<String> {
+    req.url().ok().and_then(|url| {
+        url.query_pairs()
+            .find(|(key, _)| key == TOKEN_QUERY_KEY)
+            .map(|(_, value)| value.to_string())
+    })
+}
+
+// Redirect to the dashboard without the query, carrying a session that expires on its own
+fn open_session(req: &Request, token: &str, now: i64) -> Result<Response> {
+    let mut url = req.url()?;
+    url.set_query(None);
+    let mut response = Response::empty()?.with_status(303);
+    response.headers_mut().set("Location", url.as_str())?;
+    response.headers_mut().set(
+        "Set-Cookie",
+        &format!(
+            "{SESSION_COOKIE}={}; Path={}; Max-Age={SESSION_SECS}; HttpOnly; Secure; SameSite=Strict",
+            session(token, now + SESSION_SECS),
+            routes::DASHBOARD
+        ),
+    )?;
+    response
+        .headers_mut()
+        .set("Referrer-Policy", "no-referrer")?;
+    Ok(response)
+}
+
+// <expires_at>.<hex hmac-sha256 of it keyed by the token>, removing the token from
+// AUTH_TOKENS ends its sessions too
+pub fn session(token: &str, expires_at: i64) -> String {
+    format!("{expires_at}.{}", session_signature(token, expires_at))
+}
+
+pub fn session_matches(token: &str, session: &str, now: i64) -> bool {
+    let Some((expires_at, signature)) = session.split_once('.') else {
+        return false;
+    };
+    let Ok(expires_at) = expires_at.parse::<i64>() else {
+        return false;
+    };
+    expires_at > now
+        && constant_time_eq(
+            session_signature(token, expires_at).as_bytes(),
+            signature.as_bytes(),
+        )
+}
+
+fn session_signature(token: &str, expires_at: i64) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(token.as_bytes()).expect("hmac accepts any key length");
+    mac.update(format!("{SESSION_COOKIE}:{expires_at}").as_bytes());
+    mac.finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+// Value of the named cookie in a Cookie header
+pub fn cookie(header: &str, name: &str) -> Option<String> {
+    header.split(';').find_map(|pair| {
+        let (key, value) = pair.trim().split_once('=')?;
+        (key == name).then(|| value.to_string())
+    })
+}
+
+pub fn render(
+    namespace: &str,
+    agents: &[Entry<Agent>],
+    requests: &[(String, Entry<Sdp>)],
+    now: i64,
+) -> String {
+    let mut groups = BTreeMap::<&str, Vec<&Entry<Agent>>>::new();
+    for entry in agents {
+        groups.entry(&entry.value.name).or_default().push(entry);
+    }
+
+    let mut agent_rows = String::new();
+    for (name, entries) in &groups {
+        for (i, entry) in entries.iter().enumerate() {
+            let agent = &entry.value;
+            let services = agent
+                .services
+                .iter()
+                .map(|s| format!("{} {}", s.name, service(s.is_udp, s.port)))
+                .collect::<Vec<_>>()
+                .join(", ");
+            agent_rows.push_str(&format!(
+                "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}s ago</td><td>{}</td></tr>\n",
+                if i == 0 { escape(name) } else { String::new() },
+                escape(&agent.uuid),
+                escape(&agent.os),
+                escape(&agent.version),
+                now - agent.last_seen,
+                escape(&services),
+            ));
+        }
+    }
+
+    let mut request_rows = String::new();
+    for (uuid, entry) in requests {
+        let name = agents
+            .iter()
+            .find(|a| &a.value.uuid == uuid)
+            .map_or("", |a| a.value.name.as_str());
+        request_rows.push_str(&format!(
+            "<tr><td>{}</td><td>{}</td><td>{}</td><td>{}</td><td>{}s ago</td></tr>\n",
+            escape(name),
+            escape(uuid),
+            escape(&entry.value.session),
+            service(entry.value.is_udp, entry.value.port),
+            now - entry.updated_at,
+        ));
+    }
+
+    format!(
+        r#"<!DOCTYPE html>
+<html>
+<head>
+<meta charset="utf-8">
+<meta http-equiv="refresh" content="{REFRESH_SECS}">
+<title>p2p-proxy signal: {namespace}</title>
+<style>
+body {{ font-family: sans-serif; margin: 2em; }}
+table {{ border-collapse: collapse; margin-bottom: 2em; }}
+th, td {{ border: 1px solid #ccc; padding: 4px 8px; text-align: left; }}
+</style>
+</head>
+<body>
+<h1>namespace: {namespace}</h1>
+<h2>agents ({agent_count})</h2>
+<table>
+<tr><th>name</th><th>uuid</th><th>os</th><th>version</th><th>last seen</th><th>services</th></tr>
+{agent_rows}</table>
+<h2>connection requests ({request_count})</h2>
+<table>
+<tr><th>agent</th><th>uuid</th><th>session</th><th>service</th><th>requested</th></tr>
+{request_rows}</table>
+</body>
+</html>
+"#,
+        namespace = escape(namespace),
+        agent_count = agents.len(),
+        request_count = requests.len(),
+    )
+}
+
+fn service(is_udp: bool, port: u16) -> String {
+    format!("{}/{}", if is_udp { "udp" } else { "tcp" }, port)
+}
+
+fn escape(text: &str) -> String {
+    text.replace('&', "&amp;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+        .replace('"', "&quot;")
+        .replace('\'', "&#39;")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::state::Service;
+
+    fn agent(name: &str, uuid: &str) -> Entry<Agent> {
+        Entry::new(
+            Agent {
+                uuid: uuid.to_string(),
+                name: name.to_string(),
+                os: "linux".to_string(),
+                last_seen: 90,
+                services: vec![Service {
+                    name: "ssh".to_string(),
+                    is_udp: false,
+                    port: 22,
+                }],
+                ..Default::default()
+            },
+            90,
+            300,
+        )
+    }
+
+    #[test]
+    fn render_lists_agents_and_requests() {
+        let sdp = Entry::new(
+            Sdp {
+                session: "s1".to_string(),
+                is_udp: true,
+                port: 53,
+                ..Default::default()
+            },
+            70,
+            600,
+        );
+        let html = render(
+            "team-a",
+            &[agent("office", "a"), agent("office", "b")],
+            &[("b".to_string(), sdp)],
+            100,
+        );
+
+        assert!(html.contains("<h2>agents (2)</h2>"));
+        assert!(html.contains("<td>office</td><td>a</td>"));
+        assert!(html.contains("<td></td><td>b</td>"));
+        assert!(html.contains("<td>10s ago</td><td>ssh tcp/22</td>"));
+        assert!(
+            html.contains("<td>office</td><td>b</td><td>s1</td><td>udp/53</td><td>30s ago</td>")
+        );
+    }
+
+    #[test]
+    fn sessions_expire_and_belong_to_their_token() {
+        let value = session("admin-secret", 200);
+        assert!(session_matches("admin-secret", &value, 100));
+        assert!(!session_matches("admin-secret", &value, 200));
+        assert!(!session_matches("other-secret", &value, 100));
+        // a later expiry does not carry the signature of the earlier one
+        let (_, signature) = value.split_once('.').unwrap();
+        assert!(!session_matches(
+            "admin-secret",
+            &format!("900.{signature}"),
+            100
+        ));
+        assert!(!session_matches("admin-secret", "admin-secret", 100));
+    }
+
+    #[test]
+    fn cookie_finds_the_named_value() {
+        let header = "theme=dark; dashboard_session=200.ab; other=1";
+        assert_eq!(
+            cookie(header, "dashboard_session"),
+            Some("200.ab".to_string())
+        );
+        assert_eq!(cookie(header, "missing"), None);
+        assert_eq!(cookie("dashboard_session", "dashboard_session"), None);
+    }
+
+    #[test]
+    fn render_escapes_agent_fields() {
+        let html = render("ns", &[agent("<script>", "a")], &[], 100);
+        assert!(!html.contains("<script>"));
+        assert!(html.contains("&lt;script&gt;"));
+    }
+}
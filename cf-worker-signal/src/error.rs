@@ -0,0 +1,3 @@
+pub use signal_protocol::{ErrorCode, ErrorResponse};
+
+pub type ApiResult<T> = std::result::Result<T, ErrorResponse>;
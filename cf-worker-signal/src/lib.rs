@@ -1,19 +1,74 @@
+use signal_protocol::routes;
 use worker::*;
 
+pub mod admin;
 pub mod agent;
+pub mod auth;
+pub mod candidate;
+pub mod dashboard;
+pub mod error;
+pub mod limit;
+pub mod request;
 pub mod sdp;
 pub mod state;
+pub mod storage;
+pub mod sweep;
+pub mod version;
+pub mod webhook;
 
 #[event(fetch)]
-pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
+pub async fn main(req: Request, env: Env, ctx: Context) -> Result<Response> {
     console_error_panic_hook::set_once();
 
-    let router = Router::new();
+    if let Err(e) = limit::check(&req, &env).await {
+        return e.to_response();
+    }
+
+    // handlers hand slow side effects like webhooks to ctx.wait_until
+    let router = Router::with_data(ctx);
 
     router
+        // read-only html page for browsers
+        .get_async(routes::DASHBOARD, dashboard::handle_dashboard)
+        // v2 api
+        .get_async(routes::VERSION, version::handle_query_version)
+        .get_async(routes::OPENAPI, version::handle_query_openapi)
+        .put_async(routes::AGENT, agent::handle_publish_agent_v2)
+        .get_async(routes::AGENTS, agent::handle_query_agent)
+        .delete_async(routes::AGENT, agent::handle_delete_agent_v2)
+        .put_async(routes::CLIENT_SDP, sdp::handle_publish_client_sdp_v2)
+        .get_async(routes::CLIENT_SDPS, sdp::handle_query_client_sdp)
+        .delete_async(routes::CLIENT_SDP, sdp::handle_delete_client_sdp_v2)
+        .put_async(routes::AGENT_SDP, sdp::handle_publish_agent_sdp_v2)
+        .get_async(routes::AGENT_SDPS, sdp::handle_query_agent_sdp)
+        .delete_async(routes::AGENT_SDP, sdp::handle_delete_agent_sdp_v2)
+        .put_async(
+            routes::CLIENT_CANDIDATE,
+            candidate::handle_publish_client_candidate,
+        )
+        .get_async(
+            routes::CLIENT_CANDIDATES,
+            candidate::handle_query_client_candidates,
+        )
+        .put_async(
+            routes::AGENT_CANDIDATE,
+            candidate::handle_publish_agent_candidate,
+        )
+        .get_async(
+            routes::AGENT_CANDIDATES,
+            candidate::handle_query_agent_candidates,
+        )
+        // admin api
+        .get_async(routes::ADMIN_AGENTS, admin::handle_list_agents)
+        .post_async(routes::ADMIN_AGENT_EXPIRE, admin::handle_expire_agent)
+        .get_async(routes::ADMIN_SDPS, admin::handle_list_sdps)
+        .get_async(routes::ADMIN_SWEEP, admin::handle_query_sweep)
+        .delete_async(routes::ADMIN_NAMESPACE, admin::handle_purge_namespace)
+        // v1 api, kept for clients that have not migrated yet
         .post_async("/publish/agent/:name", agent::handle_publish_agent)
         .get_async("/query/agent/:name", agent::handle_query_agent)
         .post_async("/delete/agent/:name", agent::handle_delete_agent)
+        .delete_async("/delete/agent/:name", agent::handle_delete_agent)
         .post_async("/publish/client/sdp/:uuid", sdp::handle_publish_client_sdp)
         .get_async("/query/client/sdp/:uuid", sdp::handle_query_client_sdp)
         .post_async("/delete/client/sdp/:uuid", sdp::handle_delete_client_sdp)
@@ -23,3 +78,12 @@ pub async fn main(req: Request, env: Env, _ctx: Context) -> Result<Response> {
         .run(req, env)
         .await
 }
+
+#[event(scheduled)]
+pub async fn scheduled(_event: ScheduledEvent, env: Env, _ctx: ScheduleContext) {
+    console_error_panic_hook::set_once();
+
+    if let Err(e) = sweep::run(&env).await {
+        console_error!("sweep::run() error, e: {:?}", e);
+    }
+}
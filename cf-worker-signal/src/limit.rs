@@ -0,0 +1,76 @@
+use sha2::{Digest, Sha256};
+use worker::{console_warn, Env, RateLimiter, Request};
+
+use crate::auth::bearer_token;
+use crate::error::{ApiResult, ErrorResponse};
+
+static IP_RATE_LIMITER_KEY: &str = "IP_RATE_LIMITER";
+static TOKEN_RATE_LIMITER_KEY: &str = "TOKEN_RATE_LIMITER";
+static RATE_LIMIT_PERIOD_KEY: &str = "RATE_LIMIT_PERIOD_SECS";
+static DEFAULT_RATE_LIMIT_PERIOD_SECS: u64 = 60;
+static CLIENT_IP_HEADER: &str = "CF-Connecting-IP";
+
+// Count the request against the per ip and per token limiters before any route runs
+pub async fn check(req: &Request, env: &Env) -> ApiResult<()> {
+    let retry_after = env
+        .var(RATE_LIMIT_PERIOD_KEY)
+        .ok()
+        .and_then(|v| v.to_string().parse::<u64>().ok())
+        .unwrap_or(DEFAULT_RATE_LIMIT_PERIOD_SECS);
+
+    if let Ok(Some(ip)) = req.headers().get(CLIENT_IP_HEADER) {
+        limit(env, IP_RATE_LIMITER_KEY, format!("ip:{ip}"), retry_after).await?;
+    }
+    if let Some(token) = bearer_token(req) {
+        limit(
+            env,
+            TOKEN_RATE_LIMITER_KEY,
+            format!("token:{}", token_digest(&token)),
+            retry_after,
+        )
+        .await?;
+    }
+
+    Ok(())
+}
+
+// Limiter keys may show up in logs and analytics, so they carry a digest instead of the secret
+fn token_digest(token: &str) -> String {
+    Sha256::digest(token.as_bytes())
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect()
+}
+
+// Limiters that are not bound, e.g. in local dev, leave requests unlimited
+async fn limit(env: &Env, binding: &str, key: String, retry_after: u64) -> ApiResult<()> {
+    let Ok(limiter) = env.get_binding::<RateLimiter>(binding) else {
+        return Ok(());
+    };
+
+    match limiter.limit(key).await {
+        Ok(outcome) if !outcome.success => Err(ErrorResponse::too_many_requests(
+            format!("rate limited by {binding}"),
+            retry_after,
+        )),
+        Ok(_) => Ok(()),
+        Err(e) => {
+            console_warn!("{}.limit() error, e: {:?}", binding, e);
+            Ok(())
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn token_digest_hides_the_token() {
+        assert_eq!(
+            token_digest("abc"),
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"
+        );
+        assert!(!token_digest("agent-secret").contains("agent-secret"));
+    }
+}
@@ -0,0 +1,44 @@
+use serde::de::DeserializeOwned;
+use worker::{Request, RouteContext};
+
+use crate::error::{ApiResult, ErrorResponse};
+
+static MAX_BODY_SIZE_KEY: &str = "MAX_BODY_BYTES";
+static DEFAULT_MAX_BODY_SIZE: usize = 64 * 1024;
+
+// Read and deserialize a json body, rejecting oversize or malformed payloads
+pub async fn read_json<T: DeserializeOwned, D>(
+    req: &mut Request,
+    ctx: &RouteContext<D>,
+) -> ApiResult<T> {
+    let max_body_size = ctx
+        .var(MAX_BODY_SIZE_KEY)
+        .ok()
+        .and_then(|v| v.to_string().parse::<usize>().ok())
+        .unwrap_or(DEFAULT_MAX_BODY_SIZE);
+
+    if let Ok(Some(length)) = req.headers().get("Content-Length") {
+        if length.parse::<usize>().unwrap_or(0) > max_body_size {
+            return Err(ErrorResponse::payload_too_large(format!(
+                "body larger than {max_body_size} bytes"
+            )));
+        }
+    }
+
+    let body = req
+        .bytes()
+        .await
+        .map_err(|e| ErrorResponse::bad_request(e.to_string()))?;
+    if body.len() > max_body_size {
+        return Err(ErrorResponse::payload_too_large(format!(
+            "body larger than {max_body_size} bytes"
+        )));
+    }
+
+    serde_json::from_slice(&body).map_err(|e| ErrorResponse::bad_request(e.to_string()))
+}
+
+// Route parameter by name, empty when the route does not define it
+pub fn param<D>(ctx: &RouteContext<D>, key: &str) -> String {
+    ctx.param(key).cloned().unwrap_or_default()
+}
@@ -2,78 +2,273 @@ use std::collections::HashMap;
 
 use worker::*;
 
-use crate::state::{AbstractKvStore, AppStateKvStore};
+use crate::auth::{authorize, Scope};
+use crate::error::{ApiResult, ErrorResponse};
+use crate::request::{param, read_json};
+use crate::state::{AbstractKvStore, Agent, AppStateKvStore, Sdp};
+use crate::webhook::{self, EventType, RequestedService, WebhookEvent};
 
 pub async fn handle_publish_client_sdp(
-    mut req: Request,
-    ctx: RouteContext<()>,
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    publish_client_sdp(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_query_client_sdp(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    query_client_sdp(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_delete_client_sdp(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    delete_client_sdp(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_publish_client_sdp_v2(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    publish_client_sdp_v2(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_delete_client_sdp_v2(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    delete_client_sdp_v2(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_publish_agent_sdp(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    publish_agent_sdp(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_query_agent_sdp(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    query_agent_sdp(req, ctx).await.or_else(|e| e.to_response())
+}
+
+pub async fn handle_delete_agent_sdp(req: Request, ctx: RouteContext<Context>) -> Result<Response> {
+    delete_agent_sdp(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_publish_agent_sdp_v2(
+    req: Request,
+    ctx: RouteContext<Context>,
+) -> Result<Response> {
+    publish_agent_sdp_v2(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+pub async fn handle_delete_agent_sdp_v2(
+    req: Request,
+    ctx: RouteContext<Context>,
 ) -> Result<Response> {
-    let mut key = String::new();
-    if let Some(uuid) = ctx.param("uuid") {
-        key = uuid.to_string();
-    };
+    delete_agent_sdp_v2(req, ctx)
+        .await
+        .or_else(|e| e.to_response())
+}
+
+async fn publish_client_sdp(mut req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Client)?;
+    let uuid = param(&ctx, "uuid");
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let sdp = read_json::<Sdp, _>(&mut req, &ctx).await?;
+    if kv.insert_or_update_client_sdp(&uuid, sdp.clone()).await? {
+        notify_connection_requested(&ctx, &mut kv, &token.namespace, &uuid, &sdp).await?;
+    }
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
+
+async fn query_client_sdp(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let sdps = kv.query_client_sdp(&uuid).await?;
+    Ok(Response::from_json(&sdps)?)
+}
+
+async fn delete_client_sdp(mut req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Client)?;
+    let uuid = param(&ctx, "uuid");
+    let sdp = read_json::<Sdp, _>(&mut req, &ctx).await?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    kv.delete_client_sdp(
+        &uuid,
+        &<AppStateKvStore>::format_session_key(&sdp.session, sdp.is_udp, sdp.port),
+    )
+    .await?;
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
+
+async fn publish_client_sdp_v2(
+    mut req: Request,
+    ctx: RouteContext<Context>,
+) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Client)?;
+    let uuid = param(&ctx, "uuid");
+    let session = param(&ctx, "session");
+    let mut sdp = read_json::<Sdp, _>(&mut req, &ctx).await?;
+    if sdp.session.is_empty() {
+        sdp.session = session;
+    } else if sdp.session != session {
+        return Err(ErrorResponse::bad_request(format!(
+            "sdp session {} does not match path session {session}",
+            sdp.session
+        )));
+    }
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    if kv.insert_or_update_client_sdp(&uuid, sdp.clone()).await? {
+        notify_connection_requested(&ctx, &mut kv, &token.namespace, &uuid, &sdp).await?;
+    }
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    kv.insert_or_update_client_sdp(&key, req.json().await?)
-        .await;
+async fn delete_client_sdp_v2(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Client)?;
+    let uuid = param(&ctx, "uuid");
+    let session = param(&ctx, "session");
 
-    Response::from_json(&HashMap::<String, String>::new())
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    if !kv.delete_client_sdp(&uuid, &session).await? {
+        return Err(ErrorResponse::not_found(format!(
+            "unknown client sdp {uuid}, session: {session}"
+        )));
+    }
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
+}
+
+async fn publish_agent_sdp(mut req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    kv.insert_or_update_agent_sdp(&uuid, read_json(&mut req, &ctx).await?)
+        .await?;
+
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
 }
 
-pub async fn handle_query_client_sdp(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let mut key = String::new();
-    if let Some(uuid) = ctx.param("uuid") {
-        key = uuid.to_string();
-    };
+async fn query_agent_sdp(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Client)?;
+    let uuid = param(&ctx, "uuid");
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    let sdps = kv.query_client_sdp(&key).await;
-    Response::from_json(&sdps)
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    let sdps = kv.query_agent_sdp(&uuid).await?;
+    Ok(Response::from_json(&sdps)?)
 }
 
-pub async fn handle_delete_client_sdp(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let mut key = String::new();
-    if let Some(uuid) = ctx.param("uuid") {
-        key = uuid.to_string();
-    };
+async fn delete_agent_sdp(mut req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
+    let sdp = read_json::<Sdp, _>(&mut req, &ctx).await?;
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    kv.delete_client_sdp(&key, req.json().await?).await;
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    kv.delete_agent_sdp(
+        &uuid,
+        &<AppStateKvStore>::format_session_key(&sdp.session, sdp.is_udp, sdp.port),
+    )
+    .await?;
 
-    Response::from_json(&HashMap::<String, String>::new())
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
 }
 
-pub async fn handle_publish_agent_sdp(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let mut key = String::new();
-    if let Some(uuid) = ctx.param("uuid") {
-        key = uuid.to_string();
-    };
+async fn publish_agent_sdp_v2(mut req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
+    let session = param(&ctx, "session");
+    let mut sdp = read_json::<Sdp, _>(&mut req, &ctx).await?;
+    if sdp.session.is_empty() {
+        sdp.session = session;
+    } else if sdp.session != session {
+        return Err(ErrorResponse::bad_request(format!(
+            "sdp session {} does not match path session {session}",
+            sdp.session
+        )));
+    }
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    kv.insert_or_update_agent_sdp(&key, req.json().await?).await;
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    kv.insert_or_update_agent_sdp(&uuid, sdp).await?;
 
-    Response::from_json(&HashMap::<String, String>::new())
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
 }
 
-pub async fn handle_query_agent_sdp(_req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let mut key = String::new();
-    if let Some(uuid) = ctx.param("uuid") {
-        key = uuid.to_string();
-    };
+async fn delete_agent_sdp_v2(req: Request, ctx: RouteContext<Context>) -> ApiResult<Response> {
+    let token = authorize(&req, &ctx, Scope::Agent)?;
+    let uuid = param(&ctx, "uuid");
+    token.check_uuid(&uuid)?;
+    let session = param(&ctx, "session");
+
+    let mut kv = AppStateKvStore::from_ctx(&ctx, &token.namespace)?;
+    if !kv.delete_agent_sdp(&uuid, &session).await? {
+        return Err(ErrorResponse::not_found(format!(
+            "unknown agent sdp {uuid}, session: {session}"
+        )));
+    }
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    let sdps = kv.query_agent_sdp(&key).await;
-    Response::from_json(&sdps)
+    Ok(Response::from_json(&HashMap::<String, String>::new())?)
 }
 
-pub async fn handle_delete_agent_sdp(mut req: Request, ctx: RouteContext<()>) -> Result<Response> {
-    let mut key = String::new();
-    if let Some(uuid) = ctx.param("uuid") {
-        key = uuid.to_string();
-    };
+// Tell webhooks a client asked for a tunnel to the agent with uuid
+async fn notify_connection_requested(
+    ctx: &RouteContext<Context>,
+    kv: &mut AppStateKvStore,
+    namespace: &str,
+    uuid: &str,
+    sdp: &Sdp,
+) -> ApiResult<()> {
+    // the agent lookup lists the namespace, skip it when nobody listens
+    if webhook::subscribers(&ctx.env, EventType::ConnectionRequested, namespace).is_empty() {
+        return Ok(());
+    }
 
-    let mut kv = AppStateKvStore::new(ctx.kv(AppStateKvStore::get_kv_store_key())?);
-    kv.delete_agent_sdp(&key, req.json().await?).await;
+    let agent = kv
+        .list_agents()
+        .await?
+        .into_iter()
+        .map(|entry| entry.value)
+        .find(|agent| agent.uuid == uuid)
+        .unwrap_or_else(|| Agent {
+            uuid: uuid.to_string(),
+            ..Default::default()
+        });
+    let mut event = WebhookEvent::new(EventType::ConnectionRequested, namespace, &agent);
+    event.service = Some(RequestedService {
+        is_udp: sdp.is_udp,
+        port: sdp.port,
+        session: sdp.session.clone(),
+    });
+    webhook::notify_later(ctx, event);
 
-    Response::from_json(&HashMap::<String, String>::new())
+    Ok(())
 }
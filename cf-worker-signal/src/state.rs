@@ -1,183 +1,1029 @@
 use std::collections::BTreeMap;
 
+use serde::de::IgnoredAny;
 use serde::{Deserialize, Serialize};
-use worker::kv::KvStore;
+use worker::{console_warn, Env, RouteContext};
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
-pub struct Agent {
-    pub uuid: String,
-    pub name: String,
-    pub os: String,
+use crate::error::{ApiResult, ErrorResponse};
+use crate::storage::{KvStorage, Storage};
+pub use signal_protocol::{Agent, Candidate, Sdp, Service, SweepReport};
+
+// Stored wrapper carrying the lifetime of a single agent or sdp
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct Entry<T> {
+    pub value: T,
+    pub updated_at: i64,
+    pub expires_at: i64,
 }
 
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
-pub struct Sdp {
-    pub sdp: Vec<u8>,
-    pub is_udp: bool,
-    pub port: u16,
+impl<T> Entry<T> {
+    pub fn new(value: T, now: i64, ttl: u64) -> Self {
+        Self {
+            value,
+            updated_at: now,
+            expires_at: now + ttl as i64,
+        }
+    }
+
+    pub fn is_expired(&self, now: i64) -> bool {
+        self.expires_at <= now
+    }
 }
 
 pub trait AbstractKvStore {
     fn get_kv_store_key() -> &'static str;
-    fn get_expiration_ttl() -> u64;
-    fn format_agent_key(name: &str) -> String;
-    fn format_agent_sdp_key(uuid: &str) -> String;
-    fn format_client_sdp_key(uuid: &str) -> String;
+    fn get_agent_ttl_key() -> &'static str;
+    fn get_sdp_ttl_key() -> &'static str;
+    fn get_max_sdps_key() -> &'static str;
+    fn format_sweep_report_key(namespace: &str) -> String;
+    fn get_sweep_report_ttl() -> u64;
+    fn get_default_agent_ttl() -> u64;
+    fn get_default_sdp_ttl() -> u64;
+    fn get_default_max_sdps() -> usize;
+    fn get_max_candidates() -> usize;
+    fn get_refresh_age(ttl: u64) -> i64;
+    fn get_default_namespace() -> &'static str;
+    fn format_namespace_key(namespace: &str) -> String;
+    fn format_agent_key(namespace: &str, name: &str) -> String;
+    fn format_agent_sdp_key(namespace: &str, uuid: &str) -> String;
+    fn format_client_sdp_key(namespace: &str, uuid: &str) -> String;
+    fn format_agent_candidate_key(namespace: &str, uuid: &str, session: &str) -> String;
+    fn format_client_candidate_key(namespace: &str, uuid: &str, session: &str) -> String;
     fn format_service_key(is_udp: bool, port: u16) -> String;
+    fn format_session_key(session: &str, is_udp: bool, port: u16) -> String;
+    fn format_item_key(key: &str, sub_key: &str) -> String;
+    fn format_prefix_key(key: &str) -> String;
 }
 
-pub struct AppStateKvStore {
-    kv: KvStore,
+pub struct AppStateKvStore<S: Storage = KvStorage> {
+    storage: S,
+    namespace: String,
+    agent_ttl: u64,
+    sdp_ttl: u64,
+    max_sdps: usize,
 }
 
-impl AbstractKvStore for AppStateKvStore {
+impl<S: Storage> AbstractKvStore for AppStateKvStore<S> {
     fn get_kv_store_key() -> &'static str {
         "kv_cf_worker_signal"
     }
 
-    fn get_expiration_ttl() -> u64 {
-        3600
+    fn get_agent_ttl_key() -> &'static str {
+        "AGENT_TTL_SECS"
+    }
+
+    fn get_sdp_ttl_key() -> &'static str {
+        "SDP_TTL_SECS"
+    }
+
+    fn get_max_sdps_key() -> &'static str {
+        "MAX_SDPS_PER_UUID"
+    }
+
+    // outside of every namespace, so purges and sweeps never touch it
+    fn format_sweep_report_key(namespace: &str) -> String {
+        format!("sweep:last:{}", namespace)
+    }
+
+    fn get_sweep_report_ttl() -> u64 {
+        7 * 24 * 3600
     }
 
-    fn format_agent_key(name: &str) -> String {
-        format!("agent:{}", name)
+    fn get_default_agent_ttl() -> u64 {
+        300
     }
 
-    fn format_agent_sdp_key(uuid: &str) -> String {
-        format!("agent_sdp:{}", uuid)
+    fn get_default_sdp_ttl() -> u64 {
+        600
     }
 
-    fn format_client_sdp_key(uuid: &str) -> String {
-        format!("client_sdp:{}", uuid)
+    fn get_default_max_sdps() -> usize {
+        32
+    }
+
+    // per session and side, hosts with many interfaces gather a few dozen at most
+    fn get_max_candidates() -> usize {
+        64
+    }
+
+    // unchanged items are rewritten only once they are this old, so most heartbeats cost a
+    // single read and an item always has two thirds of its ttl left for the next one
+    fn get_refresh_age(ttl: u64) -> i64 {
+        (ttl / 3) as i64
+    }
+
+    fn get_default_namespace() -> &'static str {
+        "default"
+    }
+
+    // every key starts with its tenant, so teams sharing a worker never see each other
+    fn format_namespace_key(namespace: &str) -> String {
+        format!("ns:{}", namespace)
+    }
+
+    fn format_agent_key(namespace: &str, name: &str) -> String {
+        format!("{}:agent:{}", Self::format_namespace_key(namespace), name)
+    }
+
+    fn format_agent_sdp_key(namespace: &str, uuid: &str) -> String {
+        format!(
+            "{}:agent_sdp:{}",
+            Self::format_namespace_key(namespace),
+            uuid
+        )
+    }
+
+    fn format_client_sdp_key(namespace: &str, uuid: &str) -> String {
+        format!(
+            "{}:client_sdp:{}",
+            Self::format_namespace_key(namespace),
+            uuid
+        )
+    }
+
+    fn format_agent_candidate_key(namespace: &str, uuid: &str, session: &str) -> String {
+        format!(
+            "{}:agent_candidate:{}:{}",
+            Self::format_namespace_key(namespace),
+            uuid,
+            session
+        )
+    }
+
+    fn format_client_candidate_key(namespace: &str, uuid: &str, session: &str) -> String {
+        format!(
+            "{}:client_candidate:{}:{}",
+            Self::format_namespace_key(namespace),
+            uuid,
+            session
+        )
     }
 
     fn format_service_key(is_udp: bool, port: u16) -> String {
         format!("{}:{}", if is_udp { "udp" } else { "tcp" }, port)
     }
+
+    fn format_session_key(session: &str, is_udp: bool, port: u16) -> String {
+        // clients without session support share one entry per service
+        if session.is_empty() {
+            Self::format_service_key(is_udp, port)
+        } else {
+            session.to_string()
+        }
+    }
+
+    fn format_item_key(key: &str, sub_key: &str) -> String {
+        format!("{}{}", Self::format_prefix_key(key), sub_key)
+    }
+
+    fn format_prefix_key(key: &str) -> String {
+        format!("{}:", key)
+    }
 }
 
 impl AppStateKvStore {
-    pub fn new(kv: KvStore) -> Self {
-        Self { kv }
+    pub fn from_ctx<D>(ctx: &RouteContext<D>, namespace: &str) -> worker::Result<Self> {
+        Self::from_env(&ctx.env, namespace)
     }
 
-    pub async fn insert_or_update_agent(&mut self, name: &str, agent: Agent) {
-        self.insert_or_update_generic(Self::format_agent_key(name), agent.uuid.clone(), agent)
-            .await
+    pub fn from_env(env: &Env, namespace: &str) -> worker::Result<Self> {
+        let var = |key: &str, default: u64| {
+            env.var(key)
+                .ok()
+                .and_then(|v| v.to_string().parse::<u64>().ok())
+                .unwrap_or(default)
+        };
+
+        Ok(Self::new(
+            KvStorage::new(env.kv(Self::get_kv_store_key())?),
+            namespace,
+            var(Self::get_agent_ttl_key(), Self::get_default_agent_ttl()),
+            var(Self::get_sdp_ttl_key(), Self::get_default_sdp_ttl()),
+            var(
+                Self::get_max_sdps_key(),
+                Self::get_default_max_sdps() as u64,
+            ) as usize,
+        ))
+    }
+}
+
+impl<S: Storage> AppStateKvStore<S> {
+    pub fn new(storage: S, namespace: &str, agent_ttl: u64, sdp_ttl: u64, max_sdps: usize) -> Self {
+        Self {
+            storage,
+            namespace: namespace.to_string(),
+            agent_ttl,
+            sdp_ttl,
+            max_sdps,
+        }
+    }
+
+    // Returns whether the agent is new and the expired agents of that name pruned on the way
+    pub async fn insert_or_update_agent(
+        &mut self,
+        name: &str,
+        agent: Agent,
+    ) -> ApiResult<(bool, Vec<Agent>)> {
+        check_key_segment("name", name)?;
+        check_key_segment("uuid", &agent.uuid)?;
+        self.insert_or_update_generic(
+            Self::format_agent_key(&self.namespace, name),
+            agent.uuid.clone(),
+            agent,
+            self.agent_ttl,
+            None,
+            // last_seen is stamped on every publish, so it does not count as a change
+            // and lags behind by at most a third of the agent ttl
+            |a, b| a.last_seen = b.last_seen,
+        )
+        .await
     }
 
-    pub async fn query_agent(&mut self, name: &str) -> Vec<Agent> {
-        self.query_generic(Self::format_agent_key(name)).await
+    pub async fn query_agent(&mut self, name: &str) -> ApiResult<Vec<Agent>> {
+        check_key_segment("name", name)?;
+        self.query_generic(Self::format_agent_key(&self.namespace, name))
+            .await
     }
 
-    pub async fn delete_agent(&mut self, name: &str, agent: Agent) {
-        self.delete_generic::<Agent>(Self::format_agent_key(name), agent.uuid)
-            .await;
+    pub async fn delete_agent(&mut self, name: &str, uuid: &str) -> ApiResult<bool> {
+        check_key_segment("name", name)?;
+        self.delete_generic(
+            Self::format_agent_key(&self.namespace, name),
+            uuid.to_string(),
+        )
+        .await
     }
 
-    pub async fn insert_or_update_client_sdp(&mut self, uuid: &str, sdp: Sdp) {
+    pub async fn insert_or_update_client_sdp(&mut self, uuid: &str, sdp: Sdp) -> ApiResult<bool> {
+        check_key_segment("uuid", uuid)?;
+        check_key_segment("session", &sdp.session)?;
         self.insert_or_update_generic(
-            Self::format_client_sdp_key(uuid),
-            Self::format_service_key(sdp.is_udp, sdp.port),
+            Self::format_client_sdp_key(&self.namespace, uuid),
+            Self::format_session_key(&sdp.session, sdp.is_udp, sdp.port),
             sdp,
+            self.sdp_ttl,
+            Some(self.max_sdps),
+            |_, _| {},
         )
         .await
+        .map(|(is_new, _)| is_new)
     }
 
-    pub async fn query_client_sdp(&mut self, uuid: &str) -> Vec<Sdp> {
-        self.query_generic(Self::format_client_sdp_key(uuid)).await
+    pub async fn query_client_sdp(&mut self, uuid: &str) -> ApiResult<Vec<Sdp>> {
+        check_key_segment("uuid", uuid)?;
+        self.query_generic(Self::format_client_sdp_key(&self.namespace, uuid))
+            .await
     }
 
-    pub async fn delete_client_sdp(&mut self, uuid: &str, sdp: Sdp) {
-        self.delete_generic::<Agent>(
-            Self::format_client_sdp_key(uuid),
-            Self::format_service_key(sdp.is_udp, sdp.port),
+    pub async fn delete_client_sdp(&mut self, uuid: &str, session_key: &str) -> ApiResult<bool> {
+        check_key_segment("uuid", uuid)?;
+        self.delete_generic(
+            Self::format_client_sdp_key(&self.namespace, uuid),
+            session_key.to_string(),
         )
-        .await;
+        .await
     }
 
-    pub async fn insert_or_update_agent_sdp(&mut self, uuid: &str, sdp: Sdp) {
+    pub async fn insert_or_update_agent_sdp(&mut self, uuid: &str, sdp: Sdp) -> ApiResult<bool> {
+        check_key_segment("uuid", uuid)?;
+        check_key_segment("session", &sdp.session)?;
         self.insert_or_update_generic(
-            Self::format_agent_sdp_key(uuid),
-            Self::format_service_key(sdp.is_udp, sdp.port),
+            Self::format_agent_sdp_key(&self.namespace, uuid),
+            Self::format_session_key(&sdp.session, sdp.is_udp, sdp.port),
             sdp,
+            self.sdp_ttl,
+            Some(self.max_sdps),
+            |_, _| {},
         )
         .await
+        .map(|(is_new, _)| is_new)
     }
 
-    pub async fn query_agent_sdp(&mut self, uuid: &str) -> Vec<Sdp> {
-        self.query_generic(Self::format_agent_sdp_key(uuid)).await
+    pub async fn query_agent_sdp(&mut self, uuid: &str) -> ApiResult<Vec<Sdp>> {
+        check_key_segment("uuid", uuid)?;
+        self.query_generic(Self::format_agent_sdp_key(&self.namespace, uuid))
+            .await
     }
 
-    pub async fn delete_agent_sdp(&mut self, uuid: &str, sdp: Sdp) {
-        self.delete_generic::<Agent>(
-            Self::format_agent_sdp_key(uuid),
-            Self::format_service_key(sdp.is_udp, sdp.port),
+    pub async fn delete_agent_sdp(&mut self, uuid: &str, session_key: &str) -> ApiResult<bool> {
+        check_key_segment("uuid", uuid)?;
+        self.delete_generic(
+            Self::format_agent_sdp_key(&self.namespace, uuid),
+            session_key.to_string(),
         )
-        .await;
+        .await
+    }
+
+    pub async fn insert_or_update_client_candidate(
+        &mut self,
+        uuid: &str,
+        candidate: Candidate,
+    ) -> ApiResult<bool> {
+        check_key_segment("uuid", uuid)?;
+        check_key_segment("session", &candidate.session)?;
+        self.insert_candidate_generic(
+            Self::format_client_candidate_key(&self.namespace, uuid, &candidate.session),
+            candidate,
+        )
+        .await
+    }
+
+    pub async fn query_client_candidates(
+        &mut self,
+        uuid: &str,
+        session: &str,
+    ) -> ApiResult<Vec<Candidate>> {
+        check_key_segment("uuid", uuid)?;
+        check_key_segment("session", session)?;
+        let mut candidates = self
+            .query_generic::<Candidate>(Self::format_client_candidate_key(
+                &self.namespace,
+                uuid,
+                session,
+            ))
+            .await?;
+        candidates.sort_by_key(|c| c.index);
+        Ok(candidates)
+    }
+
+    pub async fn insert_or_update_agent_candidate(
+        &mut self,
+        uuid: &str,
+        candidate: Candidate,
+    ) -> ApiResult<bool> {
+        check_key_segment("uuid", uuid)?;
+        check_key_segment("session", &candidate.session)?;
+        self.insert_candidate_generic(
+            Self::format_agent_candidate_key(&self.namespace, uuid, &candidate.session),
+            candidate,
+        )
+        .await
+    }
+
+    pub async fn query_agent_candidates(
+        &mut self,
+        uuid: &str,
+        session: &str,
+    ) -> ApiResult<Vec<Candidate>> {
+        check_key_segment("uuid", uuid)?;
+        check_key_segment("session", session)?;
+        let mut candidates = self
+            .query_generic::<Candidate>(Self::format_agent_candidate_key(
+                &self.namespace,
+                uuid,
+                session,
+            ))
+            .await?;
+        candidates.sort_by_key(|c| c.index);
+        Ok(candidates)
+    }
+
+    // Every live agent of the namespace, the empty name yields the prefix of all agents
+    pub async fn list_agents(&mut self) -> ApiResult<Vec<Entry<Agent>>> {
+        let prefix = Self::format_agent_key(&self.namespace, "");
+        Ok(self
+            .list_generic::<Agent>(&prefix)
+            .await?
+            .into_iter()
+            .map(|(_, entry)| entry)
+            .collect())
+    }
+
+    // Every live client sdp of the namespace with the uuid it is addressed to
+    pub async fn list_client_sdps(&mut self) -> ApiResult<Vec<(String, Entry<Sdp>)>> {
+        let prefix = Self::format_client_sdp_key(&self.namespace, "");
+        self.list_generic(&prefix).await
+    }
+
+    // Every live agent sdp of the namespace with the uuid of the answering agent
+    pub async fn list_agent_sdps(&mut self) -> ApiResult<Vec<(String, Entry<Sdp>)>> {
+        let prefix = Self::format_agent_sdp_key(&self.namespace, "");
+        self.list_generic(&prefix).await
+    }
+
+    // Delete every key of the namespace, returns how many were deleted
+    pub async fn purge(&mut self) -> ApiResult<usize> {
+        let keys = self
+            .list_keys(&Self::format_prefix_key(&Self::format_namespace_key(
+                &self.namespace,
+            )))
+            .await?;
+        for key in &keys {
+            self.storage.delete(key).await?;
+        }
+        Ok(keys.len())
+    }
+
+    // Delete expired and malformed entries of every namespace, kv ttls lag behind expires_at
+    // returns the counts per namespace and the namespace of every expired agent
+    pub async fn sweep(
+        &mut self,
+    ) -> ApiResult<(BTreeMap<String, SweepReport>, Vec<(String, Agent)>)> {
+        let now = Self::now();
+        let mut reports = BTreeMap::new();
+        let mut expired_agents = vec![];
+        let prefix = Self::format_namespace_key("");
+        for item_key in self.list_keys(&prefix).await? {
+            let Some(text) = self.storage.get(&item_key).await? else {
+                continue;
+            };
+            // ns:<namespace>:<kind>:...
+            let namespace = item_key.split(':').nth(1).unwrap_or_default();
+            let report = reports
+                .entry(namespace.to_string())
+                .or_insert_with(|| SweepReport {
+                    swept_at: now,
+                    ..Default::default()
+                });
+            let counter = match serde_json::from_str::<Entry<IgnoredAny>>(&text) {
+                Ok(entry) if !entry.is_expired(now) => continue,
+                Err(_) => &mut report.malformed,
+                Ok(_) => match item_key.split(':').nth(2) {
+                    Some("agent") => {
+                        if let Ok(entry) = serde_json::from_str::<Entry<Agent>>(&text) {
+                            expired_agents.push((namespace.to_string(), entry.value));
+                        }
+                        &mut report.agents
+                    }
+                    Some("client_sdp") => &mut report.client_sdps,
+                    Some("agent_sdp") => &mut report.agent_sdps,
+                    Some("client_candidate" | "agent_candidate") => &mut report.candidates,
+                    _ => &mut report.malformed,
+                },
+            };
+            self.storage.delete(&item_key).await?;
+            *counter += 1;
+        }
+        Ok((reports, expired_agents))
+    }
+
+    // Save the sweep report of a namespace, admins of other tenants never see it
+    pub async fn save_sweep_report(
+        &mut self,
+        namespace: &str,
+        report: &SweepReport,
+    ) -> ApiResult<()> {
+        let text = serde_json::to_string(report)
+            .map_err(|e| ErrorResponse::storage_error(e.to_string()))?;
+        self.storage
+            .put(
+                &Self::format_sweep_report_key(namespace),
+                text,
+                Self::get_sweep_report_ttl(),
+            )
+            .await
+    }
+
+    pub async fn query_sweep_report(&mut self) -> ApiResult<Option<SweepReport>> {
+        let key = Self::format_sweep_report_key(&self.namespace);
+        match self.storage.get(&key).await? {
+            None => Ok(None),
+            Some(text) => serde_json::from_str(&text)
+                .map(Some)
+                .map_err(|e| ErrorResponse::storage_error(e.to_string())),
+        }
     }
 
-    // Generic helper method for insert or update operations
-    async fn insert_or_update_generic<T: Serialize + for<'de> Deserialize<'de> + PartialEq>(
+    // Generic helper method for insert or update operations, returns whether the item is new
+    // and the expired siblings pruned before the write
+    // every item lives under its own key, so concurrent writers never overwrite each other
+    // an unchanged item younger than the refresh age is left alone, normalize copies the
+    // fields that may differ without being a change from the stored item into the new one
+    async fn insert_or_update_generic<
+        T: Clone + Serialize + for<'de> Deserialize<'de> + PartialEq,
+    >(
         &mut self,
         key: String,
         sub_key: String,
         value: T,
-    ) {
-        let mut items: BTreeMap<String, T> = BTreeMap::new();
-        if let Ok(option) = self.kv.get(&key).text().await {
-            if let Some(text) = option {
-                items = serde_json::from_str(&text).unwrap();
-                if let Some(existing) = items.get(&sub_key) {
-                    if existing == &value {
-                        return;
-                    }
+        ttl: u64,
+        max_items: Option<usize>,
+        normalize: impl Fn(&mut T, &T),
+    ) -> ApiResult<(bool, Vec<T>)> {
+        let now = Self::now();
+        let item_key = Self::format_item_key(&key, &sub_key);
+        if let Some(existing) = self.get_entry::<T>(&item_key).await? {
+            if !existing.is_expired(now) && now - existing.updated_at < Self::get_refresh_age(ttl) {
+                let mut normalized = value.clone();
+                normalize(&mut normalized, &existing.value);
+                if normalized == existing.value {
+                    return Ok((false, vec![]));
                 }
             }
         }
 
-        items.insert(sub_key, value);
-        self.kv
-            .put(&key, serde_json::to_string(&items).unwrap())
-            .unwrap()
-            .expiration_ttl(Self::get_expiration_ttl())
-            .execute()
-            .await
-            .unwrap();
+        let (live_keys, expired) = self.prune_generic::<T>(&key, now).await?;
+        if let Some(max_items) = max_items {
+            if live_keys.len() >= max_items && !live_keys.contains(&item_key) {
+                return Err(ErrorResponse::too_many_entries(format!(
+                    "{key} already holds {max_items} entries"
+                )));
+            }
+        }
+
+        let entry = Entry::new(value, now, ttl);
+        let text = serde_json::to_string(&entry)
+            .map_err(|e| ErrorResponse::storage_error(e.to_string()))?;
+        self.storage.put(&item_key, text, ttl).await?;
+
+        Ok((!live_keys.contains(&item_key), expired))
+    }
+
+    // Candidate variant of insert_or_update_generic, returns whether the candidate is new
+    // a session trickles dozens of them, so the siblings are only listed for the cap instead
+    // of read and pruned on every write, the sweep removes the expired ones
+    async fn insert_candidate_generic(
+        &mut self,
+        key: String,
+        candidate: Candidate,
+    ) -> ApiResult<bool> {
+        let item_key = Self::format_item_key(&key, &candidate.index.to_string());
+        let keys = self.list_keys(&Self::format_prefix_key(&key)).await?;
+        let is_new = !keys.contains(&item_key);
+        let max_items = Self::get_max_candidates();
+        if is_new && keys.len() >= max_items {
+            return Err(ErrorResponse::too_many_entries(format!(
+                "{key} already holds {max_items} entries"
+            )));
+        }
+
+        let entry = Entry::new(candidate, Self::now(), self.sdp_ttl);
+        let text = serde_json::to_string(&entry)
+            .map_err(|e| ErrorResponse::storage_error(e.to_string()))?;
+        self.storage.put(&item_key, text, self.sdp_ttl).await?;
+
+        Ok(is_new)
     }
 
     // Generic helper method for query operations
-    async fn query_generic<T: Clone + Serialize + for<'de> Deserialize<'de>>(
+    async fn query_generic<T: Serialize + for<'de> Deserialize<'de>>(
         &mut self,
         key: String,
-    ) -> Vec<T> {
-        if let Ok(option) = self.kv.get(&key).text().await {
-            if let Some(text) = option {
-                let items: BTreeMap<String, T> = serde_json::from_str(&text).unwrap();
-                return items.values().cloned().collect();
+    ) -> ApiResult<Vec<T>> {
+        let now = Self::now();
+        let mut items = vec![];
+        for item_key in self.list_keys(&Self::format_prefix_key(&key)).await? {
+            if let Some(entry) = self.get_entry::<T>(&item_key).await? {
+                if !entry.is_expired(now) {
+                    items.push(entry.value);
+                }
             }
         }
-        vec![]
+        Ok(items)
     }
 
-    // Generic helper method for delete operations
-    async fn delete_generic<T: Clone + Serialize + for<'de> Deserialize<'de>>(
+    // Generic helper method for list operations across many keys
+    // returns the first segment after prefix, the uuid for sdps, next to each live entry
+    async fn list_generic<T: Serialize + for<'de> Deserialize<'de>>(
         &mut self,
-        key: String,
-        sub_key: String,
-    ) {
-        if let Ok(option) = self.kv.get(&key).text().await {
-            if let Some(text) = option {
-                let mut items: BTreeMap<String, T> = serde_json::from_str(&text).unwrap();
-                items.remove(&sub_key);
-                self.kv
-                    .put(&key, serde_json::to_string(&items).unwrap())
+        prefix: &str,
+    ) -> ApiResult<Vec<(String, Entry<T>)>> {
+        let now = Self::now();
+        let mut items = vec![];
+        for item_key in self.list_keys(prefix).await? {
+            if let Some(entry) = self.get_entry::<T>(&item_key).await? {
+                if !entry.is_expired(now) {
+                    let rest = item_key.strip_prefix(prefix).unwrap_or(&item_key);
+                    let key = rest.split_once(':').map_or(rest, |(key, _)| key);
+                    items.push((key.to_string(), entry));
+                }
+            }
+        }
+        Ok(items)
+    }
+
+    // Generic helper method for delete operations, returns whether the item existed
+    async fn delete_generic(&mut self, key: String, sub_key: String) -> ApiResult<bool> {
+        let item_key = Self::format_item_key(&key, &sub_key);
+        if self.storage.get(&item_key).await?.is_none() {
+            return Ok(false);
+        }
+
+        self.storage.delete(&item_key).await?;
+        Ok(true)
+    }
+
+    // Delete expired siblings, kv keeps them until the sweep or the expiration grace ends
+    // returns the keys still alive and the values deleted
+    async fn prune_generic<T: Serialize + for<'de> Deserialize<'de>>(
+        &mut self,
+        key: &str,
+        now: i64,
+    ) -> ApiResult<(Vec<String>, Vec<T>)> {
+        let mut live_keys = vec![];
+        let mut expired = vec![];
+        for item_key in self.list_keys(&Self::format_prefix_key(key)).await? {
+            if let Some(entry) = self.get_entry::<T>(&item_key).await? {
+                if entry.is_expired(now) {
+                    self.storage.delete(&item_key).await?;
+                    expired.push(entry.value);
+                } else {
+                    live_keys.push(item_key);
+                }
+            }
+        }
+        Ok((live_keys, expired))
+    }
+
+    // Read a single entry, malformed entries are skipped instead of failing the request
+    async fn get_entry<T: for<'de> Deserialize<'de>>(
+        &mut self,
+        item_key: &str,
+    ) -> ApiResult<Option<Entry<T>>> {
+        match self.storage.get(item_key).await? {
+            None => Ok(None),
+            Some(text) => match serde_json::from_str(&text) {
+                Ok(entry) => Ok(Some(entry)),
+                Err(e) => {
+                    console_warn!("malformed entry {}, e: {:?}", item_key, e);
+                    Ok(None)
+                }
+            },
+        }
+    }
+
+    // List all key names starting with prefix
+    async fn list_keys(&mut self, prefix: &str) -> ApiResult<Vec<String>> {
+        self.storage.list(prefix).await
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+}
+
+// Names, namespaces, uuids and sessions are joined with ':' into keys and listed by prefix
+// so one containing ':' would match the entries of another, e.g. `foo` those of `foo:bar`
+pub fn check_key_segment(kind: &str, value: &str) -> ApiResult<()> {
+    if value.contains(':') {
+        return Err(ErrorResponse::bad_request(format!(
+            "{kind} {value} must not contain ':'"
+        )));
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use futures::executor::block_on;
+
+    use super::*;
+    use crate::error::ErrorCode;
+    use crate::storage::MemoryStorage;
+
+    fn store(namespace: &str) -> AppStateKvStore<MemoryStorage> {
+        AppStateKvStore::new(MemoryStorage::new(), namespace, 300, 600, 2)
+    }
+
+    fn agent(uuid: &str, os: &str) -> Agent {
+        Agent {
+            uuid: uuid.to_string(),
+            name: "office".to_string(),
+            os: os.to_string(),
+            ..Default::default()
+        }
+    }
+
+    fn sdp(session: &str) -> Sdp {
+        Sdp {
+            sdp: vec![1, 2, 3],
+            is_udp: false,
+            port: 22,
+            session: session.to_string(),
+            restart: 0,
+        }
+    }
+
+    #[test]
+    fn insert_merges_by_uuid() {
+        block_on(async {
+            let mut kv = store("default");
+            kv.insert_or_update_agent("office", agent("a", "linux"))
+                .await
+                .unwrap();
+            kv.insert_or_update_agent("office", agent("b", "linux"))
+                .await
+                .unwrap();
+            kv.insert_or_update_agent("office", agent("a", "windows"))
+                .await
+                .unwrap();
+
+            let mut agents = kv.query_agent("office").await.unwrap();
+            agents.sort_by(|x, y| x.uuid.cmp(&y.uuid));
+            assert_eq!(agents, vec![agent("a", "windows"), agent("b", "linux")]);
+        });
+    }
+
+    #[test]
+    fn insert_reports_new_items() {
+        block_on(async {
+            let mut kv = store("default");
+            assert!(
+                kv.insert_or_update_agent("office", agent("a", "linux"))
+                    .await
                     .unwrap()
-                    .expiration_ttl(Self::get_expiration_ttl())
-                    .execute()
+                    .0
+            );
+            assert!(
+                !kv.insert_or_update_agent("office", agent("a", "linux"))
+                    .await
+                    .unwrap()
+                    .0
+            );
+        });
+    }
+
+    #[test]
+    fn delete_reports_existence() {
+        block_on(async {
+            let mut kv = store("default");
+            kv.insert_or_update_agent("office", agent("a", "linux"))
+                .await
+                .unwrap();
+
+            assert!(kv.delete_agent("office", "a").await.unwrap());
+            assert!(!kv.delete_agent("office", "a").await.unwrap());
+            assert!(kv.query_agent("office").await.unwrap().is_empty());
+        });
+    }
+
+    #[test]
+    fn expired_entries_are_hidden_and_pruned() {
+        block_on(async {
+            let mut kv = store("default");
+            let key = AppStateKvStore::<MemoryStorage>::format_agent_key("default", "office");
+            let now = AppStateKvStore::<MemoryStorage>::now();
+            let expired = Entry::new(agent("old", "linux"), now - 600, 300);
+            kv.storage
+                .put(
+                    &AppStateKvStore::<MemoryStorage>::format_item_key(&key, "old"),
+                    serde_json::to_string(&expired).unwrap(),
+                    300,
+                )
+                .await
+                .unwrap();
+            assert!(kv.query_agent("office").await.unwrap().is_empty());
+
+            let (_, pruned) = kv
+                .insert_or_update_agent("office", agent("new", "linux"))
+                .await
+                .unwrap();
+            assert_eq!(pruned, vec![agent("old", "linux")]);
+            assert_eq!(kv.storage.len(), 1);
+        });
+    }
+
+    #[test]
+    fn unchanged_items_are_refreshed_only_when_old() {
+        block_on(async {
+            let mut kv = store("default");
+            let key = AppStateKvStore::<MemoryStorage>::format_agent_key("default", "office");
+            let item_key = AppStateKvStore::<MemoryStorage>::format_item_key(&key, "a");
+            let now = AppStateKvStore::<MemoryStorage>::now();
+            let backdated = |age: i64| {
+                serde_json::to_string(&Entry::new(agent("a", "linux"), now - age, 300)).unwrap()
+            };
+
+            kv.insert_or_update_agent("office", agent("a", "linux"))
+                .await
+                .unwrap();
+            kv.storage.put(&item_key, backdated(10), 300).await.unwrap();
+            let mut heartbeat = agent("a", "linux");
+            heartbeat.last_seen = now;
+            kv.insert_or_update_agent("office", heartbeat.clone())
+                .await
+                .unwrap();
+            let entry = kv.get_entry::<Agent>(&item_key).await.unwrap().unwrap();
+            assert_eq!(entry.updated_at, now - 10);
+
+            kv.storage
+                .put(&item_key, backdated(200), 300)
+                .await
+                .unwrap();
+            kv.insert_or_update_agent("office", heartbeat.clone())
+                .await
+                .unwrap();
+            let entry = kv.get_entry::<Agent>(&item_key).await.unwrap().unwrap();
+            assert!(entry.updated_at >= now);
+            assert_eq!(entry.value, heartbeat);
+        });
+    }
+
+    #[test]
+    fn sdp_sessions_are_capped_per_uuid() {
+        block_on(async {
+            let mut kv = store("default");
+            kv.insert_or_update_client_sdp("a", sdp("s1"))
+                .await
+                .unwrap();
+            kv.insert_or_update_client_sdp("a", sdp("s2"))
+                .await
+                .unwrap();
+            // refreshing a known session is always allowed
+            kv.insert_or_update_client_sdp("a", sdp("s1"))
+                .await
+                .unwrap();
+
+            let e = kv
+                .insert_or_update_client_sdp("a", sdp("s3"))
+                .await
+                .unwrap_err();
+            assert_eq!(e.code, ErrorCode::TooManyEntries);
+
+            assert!(kv.delete_client_sdp("a", "s2").await.unwrap());
+            kv.insert_or_update_client_sdp("a", sdp("s3"))
+                .await
+                .unwrap();
+            assert_eq!(kv.query_client_sdp("a").await.unwrap().len(), 2);
+        });
+    }
+
+    #[test]
+    fn candidates_are_ordered_per_session() {
+        block_on(async {
+            let mut kv = store("default");
+            let candidate = |session: &str, index: u32| Candidate {
+                session: session.to_string(),
+                index,
+                candidate: vec![index as u8],
+                complete: false,
+                restart: 0,
+            };
+            for c in [candidate("s1", 1), candidate("s1", 0), candidate("s2", 0)] {
+                assert!(kv.insert_or_update_client_candidate("a", c).await.unwrap());
+            }
+            // a retried put of the same index is not a new candidate
+            assert!(!kv
+                .insert_or_update_client_candidate("a", candidate("s1", 0))
+                .await
+                .unwrap());
+
+            let indexes = kv
+                .query_client_candidates("a", "s1")
+                .await
+                .unwrap()
+                .into_iter()
+                .map(|c| c.index)
+                .collect::<Vec<_>>();
+            assert_eq!(indexes, vec![0, 1]);
+            assert!(kv
+                .query_agent_candidates("a", "s1")
+                .await
+                .unwrap()
+                .is_empty());
+        });
+    }
+
+    #[test]
+    fn candidates_are_capped_per_session() {
+        block_on(async {
+            let mut kv = store("default");
+            let max = AppStateKvStore::<MemoryStorage>::get_max_candidates() as u32;
+            let candidate = |index: u32| Candidate {
+                session: "s1".to_string(),
+                index,
+                ..Default::default()
+            };
+            for index in 0..max {
+                kv.insert_or_update_agent_candidate("a", candidate(index))
                     .await
                     .unwrap();
             }
-        }
+
+            let e = kv
+                .insert_or_update_agent_candidate("a", candidate(max))
+                .await
+                .unwrap_err();
+            assert_eq!(e.code, ErrorCode::TooManyEntries);
+            // a retried put of a known index is still accepted
+            assert!(!kv
+                .insert_or_update_agent_candidate("a", candidate(0))
+                .await
+                .unwrap());
+        });
+    }
+
+    #[test]
+    fn legacy_sdps_share_one_entry_per_service() {
+        block_on(async {
+            let mut kv = store("default");
+            kv.insert_or_update_agent_sdp("a", sdp("")).await.unwrap();
+            kv.insert_or_update_agent_sdp("a", sdp("")).await.unwrap();
+
+            assert_eq!(kv.query_agent_sdp("a").await.unwrap().len(), 1);
+            let session_key = AppStateKvStore::<MemoryStorage>::format_session_key("", false, 22);
+            assert!(kv.delete_agent_sdp("a", &session_key).await.unwrap());
+        });
+    }
+
+    #[test]
+    fn namespaces_are_isolated() {
+        block_on(async {
+            let mut kv = store("team-a");
+            kv.insert_or_update_agent("office", agent("a", "linux"))
+                .await
+                .unwrap();
+            kv.insert_or_update_client_sdp("a", sdp("s1"))
+                .await
+                .unwrap();
+
+            let mut other = AppStateKvStore::new(kv.storage, "team-b", 300, 600, 2);
+            assert!(other.query_agent("office").await.unwrap().is_empty());
+            assert!(other.list_client_sdps().await.unwrap().is_empty());
+            assert_eq!(other.purge().await.unwrap(), 0);
+
+            let mut kv = AppStateKvStore::new(other.storage, "team-a", 300, 600, 2);
+            let sdps = kv.list_client_sdps().await.unwrap();
+            assert_eq!(sdps.len(), 1);
+            assert_eq!(sdps[0].0, "a");
+            assert_eq!(kv.list_agents().await.unwrap().len(), 1);
+            assert_eq!(kv.purge().await.unwrap(), 2);
+        });
+    }
+
+    #[test]
+    fn separators_in_key_segments_are_rejected() {
+        block_on(async {
+            let mut kv = store("default");
+            kv.insert_or_update_agent("office", agent("a", "linux"))
+                .await
+                .unwrap();
+
+            let e = kv
+                .insert_or_update_agent("office:lab", agent("b", "linux"))
+                .await
+                .unwrap_err();
+            assert_eq!(e.code, ErrorCode::BadRequest);
+            let e = kv
+                .insert_or_update_client_sdp("a:b", sdp("s1"))
+                .await
+                .unwrap_err();
+            assert_eq!(e.code, ErrorCode::BadRequest);
+            let e = kv
+                .insert_or_update_client_sdp("a", sdp("s1:x"))
+                .await
+                .unwrap_err();
+            assert_eq!(e.code, ErrorCode::BadRequest);
+            assert_eq!(kv.query_agent("office").await.unwrap().len(), 1);
+            assert_eq!(kv.storage.len(), 1);
+        });
+    }
+
+    #[test]
+    fn sweep_removes_expired_and_malformed_entries() {
+        block_on(async {
+            let mut kv = store("team-a");
+            kv.insert_or_update_agent("office", agent("live", "linux"))
+                .await
+                .unwrap();
+            // a zero ttl expires the entries as they are written, the storage keeps them for
+            // the sweep as it does in kv
+            let mut expired = AppStateKvStore::new(kv.storage, "team-b", 0, 0, 2);
+            expired
+                .insert_or_update_agent("office", agent("dead", "linux"))
+                .await
+                .unwrap();
+            let mut expired = AppStateKvStore::new(expired.storage, "team-a", 0, 0, 2);
+            expired
+                .insert_or_update_client_sdp("a", sdp("s1"))
+                .await
+                .unwrap();
+            let mut kv = AppStateKvStore::new(expired.storage, "team-a", 300, 600, 2);
+            let sdp_key = AppStateKvStore::<MemoryStorage>::format_client_sdp_key("team-a", "a");
+            kv.storage
+                .put(
+                    &AppStateKvStore::<MemoryStorage>::format_item_key(&sdp_key, "s2"),
+                    "not json".to_string(),
+                    600,
+                )
+                .await
+                .unwrap();
+
+            let (reports, expired_agents) = kv.sweep().await.unwrap();
+            let counts = |namespace: &str| {
+                let report = &reports[namespace];
+                (
+                    report.agents,
+                    report.client_sdps,
+                    report.agent_sdps,
+                    report.malformed,
+                )
+            };
+            assert_eq!(counts("team-a"), (0, 1, 0, 1));
+            assert_eq!(counts("team-b"), (1, 0, 0, 0));
+            assert_eq!(kv.storage.len(), 1);
+            assert_eq!(
+                expired_agents,
+                vec![("team-b".to_string(), agent("dead", "linux"))]
+            );
+
+            for (namespace, report) in &reports {
+                kv.save_sweep_report(namespace, report).await.unwrap();
+            }
+            assert_eq!(
+                kv.query_sweep_report().await.unwrap().as_ref(),
+                reports.get("team-a")
+            );
+        });
     }
 }
@@ -0,0 +1,132 @@
+use std::cell::RefCell;
+use std::collections::BTreeMap;
+
+use worker::kv::KvStore;
+
+use crate::error::ApiResult;
+
+// Key value backend of the signal store, text values expire EXPIRATION_GRACE seconds after
+// their ttl, workers run single threaded, so the futures do not need to be Send
+#[allow(async_fn_in_trait)]
+pub trait Storage {
+    // one interval of the wrangler.toml sweep cron and a minute, so the sweep sees and reports
+    // every expired entry before the backend drops it
+    const EXPIRATION_GRACE: u64 = 10 * 60 + 60;
+
+    async fn get(&self, key: &str) -> ApiResult<Option<String>>;
+    async fn put(&self, key: &str, value: String, ttl: u64) -> ApiResult<()>;
+    async fn delete(&self, key: &str) -> ApiResult<()>;
+    async fn list(&self, prefix: &str) -> ApiResult<Vec<String>>;
+}
+
+pub struct KvStorage {
+    kv: KvStore,
+}
+
+impl KvStorage {
+    // cloudflare kv rejects an expiration_ttl below 60 seconds
+    pub const MIN_EXPIRATION_TTL: u64 = 60;
+
+    pub fn new(kv: KvStore) -> Self {
+        Self { kv }
+    }
+}
+
+impl Storage for KvStorage {
+    async fn get(&self, key: &str) -> ApiResult<Option<String>> {
+        Ok(self.kv.get(key).text().await?)
+    }
+
+    async fn put(&self, key: &str, value: String, ttl: u64) -> ApiResult<()> {
+        self.kv
+            .put(key, value)?
+            .expiration_ttl((ttl + Self::EXPIRATION_GRACE).max(Self::MIN_EXPIRATION_TTL))
+            .execute()
+            .await?;
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> ApiResult<()> {
+        Ok(self.kv.delete(key).await?)
+    }
+
+    // Follow the pagination cursor until every key starting with prefix is listed
+    async fn list(&self, prefix: &str) -> ApiResult<Vec<String>> {
+        let mut names = vec![];
+        let mut cursor = None;
+        loop {
+            let mut builder = self.kv.list().prefix(prefix.to_string());
+            if let Some(c) = cursor.take() {
+                builder = builder.cursor(c);
+            }
+            let response = builder.execute().await?;
+            names.extend(response.keys.into_iter().map(|k| k.name));
+            if response.list_complete || response.cursor.is_none() {
+                break;
+            }
+            cursor = response.cursor;
+        }
+        Ok(names)
+    }
+}
+
+// In process backend for native tests and local experiments
+#[derive(Default)]
+pub struct MemoryStorage {
+    // key -> (value, unix timestamp it expires at)
+    items: RefCell<BTreeMap<String, (String, i64)>>,
+}
+
+impl MemoryStorage {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn len(&self) -> usize {
+        self.items.borrow().len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.items.borrow().is_empty()
+    }
+
+    fn now() -> i64 {
+        chrono::Utc::now().timestamp()
+    }
+}
+
+impl Storage for MemoryStorage {
+    async fn get(&self, key: &str) -> ApiResult<Option<String>> {
+        Ok(self
+            .items
+            .borrow()
+            .get(key)
+            .filter(|(_, expires_at)| *expires_at > Self::now())
+            .map(|(value, _)| value.clone()))
+    }
+
+    async fn put(&self, key: &str, value: String, ttl: u64) -> ApiResult<()> {
+        let expires_at = Self::now() + (ttl + Self::EXPIRATION_GRACE) as i64;
+        self.items
+            .borrow_mut()
+            .insert(key.to_string(), (value, expires_at));
+        Ok(())
+    }
+
+    async fn delete(&self, key: &str) -> ApiResult<()> {
+        self.items.borrow_mut().remove(key);
+        Ok(())
+    }
+
+    async fn list(&self, prefix: &str) -> ApiResult<Vec<String>> {
+        let now = Self::now();
+        Ok(self
+            .items
+            .borrow()
+            .range(prefix.to_string()..)
+            .take_while(|(key, _)| key.starts_with(prefix))
+            .filter(|(_, (_, expires_at))| *expires_at > now)
+            .map(|(key, _)| key.clone())
+            .collect())
+    }
+}
@@ -0,0 +1,34 @@
+use worker::{console_log, Env};
+
+use crate::error::ApiResult;
+use crate::state::{AbstractKvStore, AppStateKvStore};
+use crate::webhook::{self, EventType, WebhookEvent};
+
+// Run from the cron trigger, removes what crashed agents and abandoned connects left behind
+pub async fn run(env: &Env) -> ApiResult<()> {
+    let mut kv = AppStateKvStore::from_env(env, <AppStateKvStore>::get_default_namespace())?;
+    let (reports, expired_agents) = kv.sweep().await?;
+    for (namespace, report) in &reports {
+        kv.save_sweep_report(namespace, report).await?;
+    }
+    for (namespace, agent) in &expired_agents {
+        webhook::notify(
+            env,
+            &WebhookEvent::new(EventType::AgentOffline, namespace, agent),
+        )
+        .await;
+    }
+
+    for (namespace, report) in &reports {
+        console_log!(
+            "sweep removed from {}, agents: {}, client_sdps: {}, agent_sdps: {}, candidates: {}, malformed: {}",
+            namespace,
+            report.agents,
+            report.client_sdps,
+            report.agent_sdps,
+            report.candidates,
+            report.malformed
+        );
+    }
+    Ok(())
+}
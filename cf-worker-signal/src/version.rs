@@ -0,0 +1,14 @@
+use signal_protocol::{VersionResponse, API_VERSION, SUPPORTED_API_VERSIONS};
+use worker::*;
+
+pub async fn handle_query_version(_req: Request, _ctx: RouteContext<Context>) -> Result<Response> {
+    Response::from_json(&VersionResponse {
+        api_version: API_VERSION,
+        supported_api_versions: SUPPORTED_API_VERSIONS.to_vec(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+    })
+}
+
+pub async fn handle_query_openapi(_req: Request, _ctx: RouteContext<Context>) -> Result<Response> {
+    Response::from_json(&signal_protocol::openapi::document())
+}
@@ -0,0 +1,219 @@
+use hmac::{Hmac, Mac};
+use serde::{Deserialize, Serialize};
+use sha2::Sha256;
+use worker::{
+    console_warn, wasm_bindgen::JsValue, Context, Env, Fetch, Headers, Method, Request,
+    RequestInit, RouteContext,
+};
+
+use crate::state::Agent;
+
+static WEBHOOKS_KEY: &str = "WEBHOOKS";
+static SIGNATURE_HEADER: &str = "X-Signal-Signature-256";
+static SIGNATURE_PREFIX: &str = "sha256=";
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum EventType {
+    // an agent published itself for the first time since it was last gone
+    AgentOnline,
+    // an agent deleted itself, was force-expired, or was swept or pruned after missing heartbeats
+    AgentOffline,
+    // a client published an sdp to connect to an agent
+    ConnectionRequested,
+}
+
+// One entry of the WEBHOOKS secret, a json array of these
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct WebhookConfig {
+    pub url: String,
+    // hmac-sha256 key, receivers recompute the signature header with it
+    pub secret: String,
+    // events to deliver, empty means all
+    #[serde(default)]
+    pub events: Vec<EventType>,
+    // namespaces to deliver, empty means all
+    #[serde(default)]
+    pub namespaces: Vec<String>,
+}
+
+impl WebhookConfig {
+    pub fn matches(&self, event: EventType, namespace: &str) -> bool {
+        (self.events.is_empty() || self.events.contains(&event))
+            && (self.namespaces.is_empty() || self.namespaces.iter().any(|n| n == namespace))
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct RequestedService {
+    pub is_udp: bool,
+    pub port: u16,
+    pub session: String,
+}
+
+// Json body posted to every matching webhook
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct WebhookEvent {
+    pub event: EventType,
+    pub namespace: String,
+    pub name: String,
+    pub uuid: String,
+    pub timestamp: i64,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub service: Option<RequestedService>,
+}
+
+impl WebhookEvent {
+    pub fn new(event: EventType, namespace: &str, agent: &Agent) -> Self {
+        Self {
+            event,
+            namespace: namespace.to_string(),
+            name: agent.name.clone(),
+            uuid: agent.uuid.clone(),
+            timestamp: chrono::Utc::now().timestamp(),
+            service: None,
+        }
+    }
+}
+
+// Configured webhooks interested in event, empty when none are set up
+pub fn subscribers(env: &Env, event: EventType, namespace: &str) -> Vec<WebhookConfig> {
+    let Ok(secret) = env.secret(WEBHOOKS_KEY) else {
+        return vec![];
+    };
+
+    match serde_json::from_str::<Vec<WebhookConfig>>(&secret.to_string()) {
+        Ok(webhooks) => webhooks
+            .into_iter()
+            .filter(|w| w.matches(event, namespace))
+            .collect(),
+        Err(e) => {
+            console_warn!("malformed {}, e: {:?}", WEBHOOKS_KEY, e);
+            vec![]
+        }
+    }
+}
+
+// Deliver event to every subscriber, failures are logged and never fail the request
+pub async fn notify(env: &Env, event: &WebhookEvent) {
+    let webhooks = subscribers(env, event.event, &event.namespace);
+    if webhooks.is_empty() {
+        return;
+    }
+
+    let body = match serde_json::to_string(event) {
+        Ok(body) => body,
+        Err(e) => {
+            console_warn!("serde_json::to_string() error, e: {:?}", e);
+            return;
+        }
+    };
+    for webhook in webhooks {
+        if let Err(e) = post(&webhook, &body).await {
+            console_warn!("webhook {} error, e: {:?}", webhook.url, e);
+        }
+    }
+}
+
+// Deliver event once the response went out, a slow receiver never delays the request
+pub fn notify_later(ctx: &RouteContext<Context>, event: WebhookEvent) {
+    let env = ctx.env.clone();
+    ctx.data
+        .wait_until(async move { notify(&env, &event).await });
+}
+
+// Hex hmac-sha256 of body, in the form of the signature header
+pub fn sign(secret: &str, body: &str) -> String {
+    let mut mac =
+        Hmac::<Sha256>::new_from_slice(secret.as_bytes()).expect("hmac accepts any key length");
+    mac.update(body.as_bytes());
+    let digest = mac
+        .finalize()
+        .into_bytes()
+        .iter()
+        .map(|b| format!("{b:02x}"))
+        .collect::<String>();
+    format!("{SIGNATURE_PREFIX}{digest}")
+}
+
+async fn post(webhook: &WebhookConfig, body: &str) -> worker::Result<()> {
+    let mut headers = Headers::new();
+    headers.set("Content-Type", "application/json")?;
+    headers.set(SIGNATURE_HEADER, &sign(&webhook.secret, body))?;
+
+    let mut init = RequestInit::new();
+    init.with_method(Method::Post)
+        .with_headers(headers)
+        .with_body(Some(JsValue::from_str(body)));
+    let response = Fetch::Request(Request::new_with_init(&webhook.url, &init)?)
+        .send()
+        .await?;
+    if !(200..300).contains(&response.status_code()) {
+        return Err(worker::Error::RustError(format!(
+            "status: {}",
+            response.status_code()
+        )));
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sign_matches_rfc_4231() {
+        assert_eq!(
+            sign("Jefe", "what do ya want for nothing?"),
+            "sha256=5bdcc146bf60754e6a042426089575c75a003f089d2739839dec58b964ec3843"
+        );
+    }
+
+    #[test]
+    fn sign_depends_on_secret_and_body() {
+        let body = r#"{"event":"agent_online"}"#;
+        assert_ne!(sign("a", body), sign("b", body));
+        assert_ne!(sign("a", body), sign("a", "{}"));
+    }
+
+    #[test]
+    fn matches_filters_events_and_namespaces() {
+        let all = WebhookConfig::default();
+        assert!(all.matches(EventType::AgentOnline, "team-a"));
+
+        let webhook = WebhookConfig {
+            events: vec![EventType::AgentOffline],
+            namespaces: vec!["team-a".to_string()],
+            ..Default::default()
+        };
+        assert!(webhook.matches(EventType::AgentOffline, "team-a"));
+        assert!(!webhook.matches(EventType::AgentOnline, "team-a"));
+        assert!(!webhook.matches(EventType::AgentOffline, "team-b"));
+    }
+
+    #[test]
+    fn event_serializes_without_empty_service() {
+        let agent = Agent {
+            uuid: "u".to_string(),
+            name: "office".to_string(),
+            ..Default::default()
+        };
+        let mut event = WebhookEvent::new(EventType::AgentOnline, "default", &agent);
+        event.timestamp = 1;
+        assert_eq!(
+            serde_json::to_string(&event).unwrap(),
+            r#"{"event":"agent_online","namespace":"default","name":"office","uuid":"u","timestamp":1}"#
+        );
+
+        event.event = EventType::ConnectionRequested;
+        event.service = Some(RequestedService {
+            is_udp: false,
+            port: 22,
+            session: "s".to_string(),
+        });
+        assert!(serde_json::to_string(&event)
+            .unwrap()
+            .ends_with(r#""service":{"is_udp":false,"port":22,"session":"s"}}"#));
+    }
+}
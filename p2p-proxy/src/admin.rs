@@ -0,0 +1,68 @@
+use anyhow::Result;
+
+use crate::{command::AdminCommands, data::Configurations, http_client};
+
+pub async fn process(command: AdminCommands) -> Result<()> {
+    let config = Configurations::load_file(false);
+    http_client::handshake(&config).await?;
+
+    match command {
+        AdminCommands::Agents {} => {
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            for agent in http_client::admin_list_agents(&config).await? {
+                tracing::info!(
+                    "name: {}, uuid: {}, os: {}, version: {}, last_seen: {}s ago, expires_in: {}s",
+                    agent.name,
+                    agent.uuid,
+                    agent.os,
+                    agent.version,
+                    now - agent.last_seen,
+                    agent.expires_at - now
+                );
+            }
+        }
+        AdminCommands::Sdps {} => {
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            for sdp in http_client::admin_list_sdps(&config).await? {
+                tracing::info!(
+                    "kind: {}, uuid: {}, session: {}, is_udp: {}, port: {}, size: {}, age: {}s, expires_in: {}s",
+                    sdp.kind,
+                    sdp.uuid,
+                    sdp.session,
+                    sdp.is_udp,
+                    sdp.port,
+                    sdp.size,
+                    sdp.age,
+                    sdp.expires_at - now
+                );
+            }
+        }
+        AdminCommands::Sweep {} => {
+            let report = http_client::admin_query_sweep(&config).await?;
+            let now = time::OffsetDateTime::now_utc().unix_timestamp();
+            tracing::info!(
+                "swept {}s ago, agents: {}, client_sdps: {}, agent_sdps: {}, candidates: {}, malformed: {}",
+                now - report.swept_at,
+                report.agents,
+                report.client_sdps,
+                report.agent_sdps,
+                report.candidates,
+                report.malformed
+            );
+        }
+        AdminCommands::Expire { name, uuid } => {
+            http_client::admin_expire_agent(&config, &name, &uuid).await?;
+            tracing::info!("expired agent name: {}, uuid: {}", name, uuid);
+        }
+        AdminCommands::Purge { namespace } => {
+            let response = http_client::admin_purge_namespace(&config, &namespace).await?;
+            tracing::info!(
+                "purged namespace: {}, deleted: {}",
+                response.namespace,
+                response.deleted
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -3,7 +3,6 @@ use aes_gcm::{
     Aes256Gcm, Key, Nonce,
 };
 use anyhow::Result;
-use getrandom;
 
 static NONCE_SIZE: usize = 12;
 
@@ -13,7 +12,7 @@ pub struct AesEncryption {
 
 impl AesEncryption {
     pub fn new(cipher_key: &str) -> Self {
-        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(&cipher_key.as_bytes()));
+        let cipher = Aes256Gcm::new(Key::<Aes256Gcm>::from_slice(cipher_key.as_bytes()));
         Self { cipher }
     }
 
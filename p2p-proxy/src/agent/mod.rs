@@ -1,18 +1,13 @@
 use std::collections::HashSet;
 
 use anyhow::Result;
+use signal_protocol::Agent;
 use tokio::{
     select,
-    time::{sleep, Duration},
+    time::{sleep, Duration, Instant},
 };
-use tracing;
 
-use crate::{
-    aes::AesEncryption,
-    candidate::IceEndpoint,
-    data::{Agent, Configurations},
-    http_client,
-};
+use crate::{aes::AesEncryption, candidate::IceEndpoint, data::Configurations, http_client};
 
 mod proxy;
 
@@ -21,13 +16,21 @@ pub async fn process() -> Result<()> {
     if config.signal_server_url.is_empty() {
         return Err(anyhow::anyhow!("config.signal_server_url.is_empty()"));
     }
+    http_client::handshake(&config).await?;
 
     let agent = Agent {
         uuid: config.uuid.clone(),
         name: config.name.clone(),
         os: config.os.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        services: config.services.clone(),
+        ..Default::default()
     };
-    http_client::publish_agent(&config, &agent);
+    // a failed publish is retried by the next heartbeat
+    if let Err(e) = http_client::publish_agent(&config, &agent).await {
+        tracing::error!("http_client::publish_agent() error, e: {:?}", e);
+    }
+    let mut last_heartbeat = Instant::now();
 
     let mut last_local_ice_endpoint = None;
     let mut last_local_candidate_strings = String::new();
@@ -37,27 +40,42 @@ pub async fn process() -> Result<()> {
             _ = tokio::signal::ctrl_c() => {
                 tracing::info!("tokio::signal::ctrl_c()");
 
-                http_client::delete_agent(&config, &agent);
+                if let Err(e) = http_client::delete_agent(&config, &agent).await {
+                    tracing::error!("http_client::delete_agent() error, e: {:?}", e);
+                }
 
-                let remote_sdps = http_client::query_client_sdp(&config, &config.uuid);
+                let remote_sdps = http_client::query_client_sdp(&config, &config.uuid).await.unwrap_or_default();
                 for sdp in remote_sdps {
-                    http_client::delete_agent_sdp(&config, &config.uuid, &sdp);
+                    if let Err(e) = http_client::delete_agent_sdp(&config, &config.uuid, &sdp).await {
+                        tracing::error!("http_client::delete_agent_sdp() error, e: {:?}", e);
+                    }
                 }
 
                 break;
             }
-            _ = async {
-                let remote_sdps = http_client::query_client_sdp(&config, &config.uuid);
+            result = async {
+                // refresh agent presence
+                if last_heartbeat.elapsed() >= Duration::from_secs(config.heartbeat_interval_secs) {
+                    if let Err(e) = http_client::publish_agent(&config, &agent).await {
+                        tracing::error!("http_client::publish_agent() error, e: {:?}", e);
+                    }
+                    last_heartbeat = Instant::now();
+                }
+
+                let remote_sdps = http_client::query_client_sdp(&config, &config.uuid).await.unwrap_or_else(|e| {
+                    tracing::error!("http_client::query_client_sdp() error, e: {:?}", e);
+                    vec![]
+                });
 
                 let mut candidates = vec![String::new(); remote_sdps.len()];
                 let mut decrypted_sdps = vec![String::new(); remote_sdps.len()];
                 for i in 0..remote_sdps.len() {
-                    match AesEncryption::new(&config.password).decrypt(&remote_sdps[i].sdp.as_slice()) {
+                    match AesEncryption::new(&config.password).decrypt(remote_sdps[i].sdp.as_slice()) {
                         Err(e) => {
                             tracing::error!("AesEncryption::new(&config.password).decrypt() error, e: {:?}", e);
                         }
                         Ok(text) => {
-                            candidates[i] = IceEndpoint::to_unique_string(&text, remote_sdps[i].is_udp, remote_sdps[i].port)?;
+                            candidates[i] = IceEndpoint::to_unique_string(&text, &remote_sdps[i].session, remote_sdps[i].is_udp, remote_sdps[i].port)?;
                             decrypted_sdps[i] = text;
                         }
                     }
@@ -74,7 +92,10 @@ pub async fn process() -> Result<()> {
                                     tracing::error!("IceEndpoint::from_str() error, e: {:?}", e);
                                 }
                                 Ok(_remote_ice_endpoint) => {
-                                    proxy::proxy(&config, &local_ice_endpoint, &remote_sdps[i]).await?;
+                                    // the answer is published again once the remote sdps change
+                                    if let Err(e) = proxy::proxy(&config, &local_ice_endpoint, &remote_sdps[i]).await {
+                                        tracing::error!("proxy::proxy() error, e: {:?}", e);
+                                    }
                                 }
                             }
                         }
@@ -109,9 +130,15 @@ pub async fn process() -> Result<()> {
                 sleep(Duration::from_secs(10)).await;
 
                 Ok::<_, anyhow::Error>(())
-            } => {}
+            } => {
+                // keep the polling cadence when a pass fails early
+                if let Err(e) = result {
+                    tracing::error!("agent loop error, e: {:?}", e);
+                    sleep(Duration::from_secs(10)).await;
+                }
+            }
         }
     }
 
-    return Ok(());
+    Ok(())
 }
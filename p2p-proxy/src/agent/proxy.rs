@@ -1,11 +1,7 @@
 use anyhow::Result;
+use signal_protocol::Sdp;
 
-use crate::{
-    aes::AesEncryption,
-    candidate::IceEndpoint,
-    data::{Configurations, Sdp},
-    http_client,
-};
+use crate::{aes::AesEncryption, candidate::IceEndpoint, data::Configurations, http_client};
 
 pub async fn proxy(
     config: &Configurations,
@@ -17,7 +13,7 @@ pub async fn proxy(
 
     let mut local_sdp = remote_sdp.clone();
     local_sdp.sdp = cipher_sdp.clone();
-    http_client::publish_agent_sdp(config, &config.uuid, &local_sdp);
+    http_client::publish_agent_sdp(config, &config.uuid, &local_sdp).await?;
 
-    return Ok(());
+    Ok(())
 }
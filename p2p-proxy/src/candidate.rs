@@ -1,4 +1,5 @@
-use std::collections::HashMap;
+use std::collections::{hash_map::Entry, HashMap};
+use std::fmt;
 use std::sync::Arc;
 
 use anyhow::Result;
@@ -18,24 +19,33 @@ pub struct IceEndpoint {
     pub candidates: Vec<Arc<dyn Candidate + Send + Sync>>,
 }
 
-impl IceEndpoint {
-    pub fn to_string(&self) -> String {
-        return self
+impl fmt::Display for IceEndpoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let text = self
             .candidates
             .iter()
             .map(|c| c.marshal())
             .collect::<Vec<String>>()
             .join(CANDIDATE_LINE_DELIMITER);
+        f.write_str(&text)
     }
+}
 
-    pub fn to_unique_string(s: &str, is_udp: bool, proxy_port: u16) -> Result<String> {
-        let candidate = unmarshal_candidate(&s)?;
+impl IceEndpoint {
+    pub fn to_unique_string(
+        s: &str,
+        session: &str,
+        is_udp: bool,
+        proxy_port: u16,
+    ) -> Result<String> {
+        let candidate = unmarshal_candidate(s)?;
         Ok(format!(
-            "candidate_type: {}, network_type: {}, address: {}, port:{}, is_udp: {}, proxy_port: {}",
+            "candidate_type: {}, network_type: {}, address: {}, port:{}, session: {}, is_udp: {}, proxy_port: {}",
             candidate.candidate_type(),
             candidate.network_type(),
             candidate.address(),
             candidate.port(),
+            session,
             is_udp,
             proxy_port
         ))
@@ -55,10 +65,10 @@ impl IceEndpoint {
             })
             .collect();
         results.sort();
-        return results;
+        results
     }
 
-    pub async fn from_str(text: &str, config: &Configurations) -> Result<Self> {
+    pub async fn from_str(text: &str, _config: &Configurations) -> Result<Self> {
         // // setup ice agent config
         // let ice_agent_config = AgentConfig {
         //     urls: config
@@ -85,15 +95,15 @@ impl IceEndpoint {
         let candidates = text
             .split(CANDIDATE_LINE_DELIMITER)
             .filter(|s| !s.is_empty())
-            .map(|s| unmarshal_candidate(s))
+            .map(unmarshal_candidate)
             .collect::<Result<Vec<_>, _>>()?
             .into_iter()
             .map(|c| Arc::new(c) as Arc<dyn Candidate + Send + Sync>)
             .collect();
 
-        return Ok(IceEndpoint {
+        Ok(IceEndpoint {
             /*agent,*/ candidates,
-        });
+        })
     }
 
     pub async fn collect(config: &Configurations, loops: u32) -> Result<Self> {
@@ -108,7 +118,7 @@ impl IceEndpoint {
                     .map(|(is_udp, host, port)| Url {
                         scheme: webrtc_ice::url::SchemeType::Stun,
                         host: host.clone(),
-                        port: port.clone(),
+                        port: *port,
                         proto: if *is_udp {
                             ProtoType::Udp
                         } else {
@@ -171,10 +181,10 @@ impl IceEndpoint {
     > {
         // sort candidates by priority
         let mut local_candidates = self.candidates.clone();
-        local_candidates.sort_by(|a, b| b.priority().cmp(&a.priority()));
+        local_candidates.sort_by_key(|c| std::cmp::Reverse(c.priority()));
 
         let mut remote_candidates = remote.candidates.clone();
-        remote_candidates.sort_by(|a, b| b.priority().cmp(&a.priority()));
+        remote_candidates.sort_by_key(|c| std::cmp::Reverse(c.priority()));
 
         // test candidate pairs
         let mut unique_ports = HashMap::new();
@@ -203,12 +213,12 @@ impl IceEndpoint {
         remote_candidate: Arc<dyn Candidate + Send + Sync>,
         unique_ports: &mut HashMap<u16, UdpSocket>,
     ) -> bool {
-        if !unique_ports.contains_key(&local_candidate.port()) {
+        if let Entry::Vacant(e) = unique_ports.entry(local_candidate.port()) {
             // bind udp socket to local candidate
             let local_address = format!("0.0.0.0:{}", local_candidate.port());
             match tokio::net::UdpSocket::bind(&local_address).await {
                 Ok(sock) => {
-                    unique_ports.insert(local_candidate.port(), sock);
+                    e.insert(sock);
                 }
                 Err(e) => {
                     tracing::error!(
@@ -246,17 +256,15 @@ impl IceEndpoint {
                 .recv_from(&mut buf)
                 .await
             {
-                Ok((_n, _addr)) => {
-                    return true;
-                }
+                Ok((_n, _addr)) => true,
                 Err(e) => {
                     tracing::error!("socket.recv_from() error, e: {:?}", e);
-                    return false;
+                    false
                 }
             }
         })
         .await;
 
-        return result.unwrap_or(false);
+        result.unwrap_or(false)
     }
 }
@@ -1,26 +1,23 @@
 use anyhow::Result;
+use signal_protocol::Sdp;
 use tokio::{
     select,
     time::{sleep, Duration},
 };
 
-use crate::{
-    aes::AesEncryption,
-    candidate::IceEndpoint,
-    data::{Configurations, Sdp},
-    http_client,
-};
+use crate::{aes::AesEncryption, candidate::IceEndpoint, data::Configurations, http_client};
 
 pub async fn process(
     name: &str,
     uuid: &str,
     udp: bool,
-    local_port: u16,
+    _local_port: u16,
     remote_port: u16,
 ) -> Result<()> {
     let config = Configurations::load_file(false);
+    http_client::handshake(&config).await?;
 
-    let mut agents = http_client::query_agent(&config, name);
+    let mut agents = http_client::query_agent(&config, name).await?;
     if agents.is_empty() {
         let s = "agents.is_empty()";
         tracing::error!(s);
@@ -31,10 +28,7 @@ pub async fn process(
             tracing::error!(s);
             return Err(anyhow::anyhow!(s));
         } else {
-            agents = agents
-                .into_iter()
-                .filter(|agent| agent.uuid == uuid)
-                .collect();
+            agents.retain(|agent| agent.uuid == uuid);
             if agents.is_empty() {
                 let s =
                     "agents.into_iter().filter(|agent| agent.uuid == uuid).collect().is_empty()";
@@ -44,28 +38,39 @@ pub async fn process(
         }
     }
 
+    let uuid = agents[0].uuid.clone();
+    let session = uuid::Uuid::new_v4().to_string();
+    tracing::info!("connect to agent: {}, session: {}", uuid, session);
+
     let local_ice_endpoint = IceEndpoint::collect(&config, 5).await?;
     let sdp = Sdp {
         is_udp: udp,
         port: remote_port,
         sdp: AesEncryption::new(&config.password).encrypt(&local_ice_endpoint.to_string())?,
+        session: session.clone(),
+        restart: 0,
     };
-    http_client::publish_client_sdp(&config, uuid, &sdp);
+    http_client::publish_client_sdp(&config, &uuid, &sdp).await?;
 
     loop {
         select! {
             _ = tokio::signal::ctrl_c() => {
                 tracing::info!("tokio::signal::ctrl_c()");
 
-                http_client::delete_client_sdp(&config, uuid, &sdp);
+                if let Err(e) = http_client::delete_client_sdp(&config, &uuid, &sdp).await {
+                    tracing::error!("http_client::delete_client_sdp() error, e: {:?}", e);
+                }
 
                 break;
             }
             _ = async {
-                let sdps = http_client::query_agent_sdp(&config, uuid);
-                if !sdps.is_empty() {
-                    let sdp = &sdps[0];
-                    let s = AesEncryption::new(&config.password).decrypt(&sdp.sdp.as_slice())?;
+                let sdps = http_client::query_agent_sdp(&config, &uuid).await.unwrap_or_else(|e| {
+                    tracing::error!("http_client::query_agent_sdp() error, e: {:?}", e);
+                    vec![]
+                });
+                // only the answer addressed to this session
+                if let Some(sdp) = sdps.iter().find(|s| s.session == session) {
+                    let s = AesEncryption::new(&config.password).decrypt(sdp.sdp.as_slice())?;
                     let remote_ice_endpoint = IceEndpoint::from_str(&s, &config).await?;
                     match local_ice_endpoint.test(&remote_ice_endpoint).await {
                         Err(e) => {
@@ -91,5 +96,5 @@ pub async fn process(
         }
     }
 
-    return Ok(());
+    Ok(())
 }
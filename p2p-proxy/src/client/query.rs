@@ -4,9 +4,28 @@ use crate::{data::Configurations, http_client};
 
 pub async fn process(name: &str) -> Result<()> {
     let config = Configurations::load_file(false);
+    http_client::handshake(&config).await?;
 
-    let agents = http_client::query_agent(&config, name);
-    tracing::info!("agents: {:?}", agents);
+    let agents = http_client::query_agent(&config, name).await?;
+    if agents.is_empty() {
+        tracing::info!("no agents named {}", name);
+    }
+    let now = time::OffsetDateTime::now_utc().unix_timestamp();
+    for agent in agents {
+        tracing::info!(
+            "name: {}, uuid: {}, os: {}, version: {}, status: {}, last_seen: {}s ago",
+            agent.name,
+            agent.uuid,
+            agent.os,
+            agent.version,
+            if agent.is_online(config.heartbeat_interval_secs) {
+                "online"
+            } else {
+                "stale"
+            },
+            now - agent.last_seen
+        );
+    }
 
-    return Ok(());
+    Ok(())
 }
@@ -23,6 +23,12 @@ pub enum Commands {
         name: String,
     },
 
+    /// inspect and manage the signal server with an admin token
+    Admin {
+        #[command(subcommand)]
+        command: AdminCommands,
+    },
+
     /// test api
     Test {
         /// the url path of the api
@@ -61,3 +67,33 @@ pub enum Commands {
         remote_port: u16,
     },
 }
+
+#[derive(Subcommand)]
+pub enum AdminCommands {
+    /// list all agents with their last seen time
+    Agents {},
+
+    /// list pending client and agent sdps with sizes and ages
+    Sdps {},
+
+    /// show what the latest scheduled cleanup removed
+    Sweep {},
+
+    /// remove an agent before its ttl runs out
+    Expire {
+        /// the name of the agent
+        #[arg(long)]
+        name: String,
+
+        /// the uuid of the agent
+        #[arg(long)]
+        uuid: String,
+    },
+
+    /// delete every agent and sdp of a namespace
+    Purge {
+        /// the namespace to purge, must be the namespace of the token
+        #[arg(long)]
+        namespace: String,
+    },
+}
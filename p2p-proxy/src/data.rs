@@ -1,25 +1,12 @@
-use sha1::{Digest, Sha1};
+use sha1::Digest;
 
 use config_file_derives::ConfigFile;
-use config_file_types;
 use serde::{Deserialize, Serialize};
+use signal_protocol::{routes, Service};
 
 static AGENT_CONFIG_PATH: &str = "agent.json";
 static CLIENT_CONFIG_PATH: &str = "client.json";
-
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
-pub struct Agent {
-    pub uuid: String,
-    pub name: String,
-    pub os: String,
-}
-
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
-pub struct Sdp {
-    pub sdp: Vec<u8>,
-    pub is_udp: bool,
-    pub port: u16,
-}
+static DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 60;
 
 #[derive(Debug, Default, Serialize, Deserialize, ConfigFile)]
 #[config_file_ext("json")]
@@ -35,16 +22,46 @@ pub struct Configurations {
 
     pub stun_server_urls: Vec<(bool, String, u16)>,
 
+    #[serde(default)]
+    pub heartbeat_interval_secs: u64,
+
+    // services advertised with the agent info, see Service
+    #[serde(default)]
+    pub services: Vec<Service>,
+
     pub signal_server_url: String,
-    pub publish_agent_url: String,
-    pub query_agent_url: String,
-    pub delete_agent_url: String,
-    pub publish_client_sdp_url: String,
-    pub query_client_sdp_url: String,
-    pub delete_client_sdp_url: String,
-    pub publish_agent_sdp_url: String,
-    pub query_agent_sdp_url: String,
-    pub delete_agent_sdp_url: String,
+    // bearer token sent to the signal server, empty when it runs without auth
+    #[serde(default)]
+    pub token: String,
+    #[serde(default)]
+    pub version_url: String,
+    #[serde(default)]
+    pub agents_url: String,
+    #[serde(default)]
+    pub client_sdps_url: String,
+    #[serde(default)]
+    pub agent_sdps_url: String,
+    #[serde(default)]
+    pub admin_url: String,
+    // v1 urls superseded by the v2 urls above, a path prefix in front of them moves there
+    #[serde(default, skip_serializing)]
+    publish_agent_url: String,
+    #[serde(default, skip_serializing)]
+    query_agent_url: String,
+    #[serde(default, skip_serializing)]
+    delete_agent_url: String,
+    #[serde(default, skip_serializing)]
+    publish_client_sdp_url: String,
+    #[serde(default, skip_serializing)]
+    query_client_sdp_url: String,
+    #[serde(default, skip_serializing)]
+    delete_client_sdp_url: String,
+    #[serde(default, skip_serializing)]
+    publish_agent_sdp_url: String,
+    #[serde(default, skip_serializing)]
+    query_agent_sdp_url: String,
+    #[serde(default, skip_serializing)]
+    delete_agent_sdp_url: String,
 }
 
 impl Configurations {
@@ -90,50 +107,60 @@ impl Configurations {
             update = true;
             config.stun_server_urls = vec![(true, String::from("stun.l.google.com"), 19302)];
         }
-        if config.signal_server_url.is_empty() {
-            tracing::error!("config.signal_server_url.is_empty()");
-        }
-        if config.publish_agent_url.is_empty() {
+        if config.heartbeat_interval_secs == 0 {
             update = true;
-            config.publish_agent_url = String::from("/publish/agent");
+            config.heartbeat_interval_secs = DEFAULT_HEARTBEAT_INTERVAL_SECS;
         }
-        if config.query_agent_url.is_empty() {
-            update = true;
-            config.query_agent_url = String::from("/query/agent");
-        }
-        if config.delete_agent_url.is_empty() {
-            update = true;
-            config.delete_agent_url = String::from("/delete/agent");
-        }
-        if config.publish_client_sdp_url.is_empty() {
-            update = true;
-            config.publish_client_sdp_url = String::from("/publish/client/sdp");
+        if config.signal_server_url.is_empty() {
+            tracing::error!("config.signal_server_url.is_empty()");
         }
-        if config.query_client_sdp_url.is_empty() {
+        let v1_urls = [
+            &config.publish_agent_url,
+            &config.query_agent_url,
+            &config.delete_agent_url,
+            &config.publish_client_sdp_url,
+            &config.query_client_sdp_url,
+            &config.delete_client_sdp_url,
+            &config.publish_agent_sdp_url,
+            &config.query_agent_sdp_url,
+            &config.delete_agent_sdp_url,
+        ];
+        let prefix = v1_urls
+            .iter()
+            .find_map(|url| routes::v1_url_prefix(url))
+            .unwrap_or_default()
+            .to_string();
+        if let Some(url) = v1_urls
+            .iter()
+            .find(|url| !url.is_empty() && routes::v1_url_prefix(url).is_none())
+        {
+            tracing::warn!("v1 url {} can not be migrated to a v2 url", url);
+        }
+        if config.version_url.is_empty() {
             update = true;
-            config.query_client_sdp_url = String::from("/query/client/sdp");
+            config.version_url = format!("{}{}", prefix, routes::VERSION_URL);
         }
-        if config.delete_client_sdp_url.is_empty() {
+        if config.agents_url.is_empty() {
             update = true;
-            config.delete_client_sdp_url = String::from("/delete/client/sdp");
+            config.agents_url = format!("{}{}", prefix, routes::AGENTS_URL);
         }
-        if config.publish_agent_sdp_url.is_empty() {
+        if config.client_sdps_url.is_empty() {
             update = true;
-            config.publish_agent_sdp_url = String::from("/publish/agent/sdp");
+            config.client_sdps_url = format!("{}{}", prefix, routes::CLIENT_SDPS_URL);
         }
-        if config.query_agent_sdp_url.is_empty() {
+        if config.agent_sdps_url.is_empty() {
             update = true;
-            config.query_agent_sdp_url = String::from("/query/agent/sdp");
+            config.agent_sdps_url = format!("{}{}", prefix, routes::AGENT_SDPS_URL);
         }
-        if config.delete_agent_sdp_url.is_empty() {
+        if config.admin_url.is_empty() {
             update = true;
-            config.delete_agent_sdp_url = String::from("/delete/agent/sdp");
+            config.admin_url = format!("{}{}", prefix, routes::ADMIN_URL);
         }
 
         if update {
             config.dump(true, false);
         }
 
-        return config;
+        config
     }
 }
@@ -1,179 +1,400 @@
-use serde_json;
-use ureq;
+use anyhow::Result;
+use serde::de::{DeserializeOwned, IgnoredAny};
 
-use crate::data::{Agent, Configurations, Sdp};
+use signal_protocol::{
+    routes, Agent, AgentSummary, ErrorResponse, PurgeResponse, Sdp, SdpSummary, SweepReport,
+    VersionResponse, API_VERSION,
+};
+
+use crate::data::Configurations;
 
 static HTTP_WRITE_TIEOUT_SECS: u64 = 5;
 static HTTP_READ_TIMEOUT_SECS: u64 = 15;
 static HTTP_HEADER_KEY_CONTENT_TYPE: &str = "Content-Type";
 static HTTP_HEADER_VALUE_APP_JSON: &str = "application/json";
+static HTTP_HEADER_KEY_AUTHORIZATION: &str = "Authorization";
+static HTTP_HEADER_KEY_RETRY_AFTER: &str = "Retry-After";
+static HTTP_MAX_RETRIES: u32 = 3;
+static HTTP_MAX_RETRY_AFTER_SECS: u64 = 60;
 
-pub fn publish_agent(config: &Configurations, agent: &Agent) {
-    let url = format!(
-        "{}{}/{}",
-        config.signal_server_url, config.publish_agent_url, agent.name
-    );
+pub async fn query_version(config: &Configurations) -> Result<VersionResponse> {
+    let url = format!("{}{}", config.signal_server_url, config.version_url);
 
-    let a = ureq::AgentBuilder::new()
-        .try_proxy_from_env(true)
-        .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
-        .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
+    send_with_retry(|| build_request(config, "GET", &url), None).await
+}
 
-    let _response = a
-        .post(&url)
-        .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
-        .send_string(&serde_json::to_string(agent).unwrap());
+// Make sure the signal server speaks the api version this client was built for
+pub async fn handshake(config: &Configurations) -> Result<()> {
+    let version = query_version(config).await.map_err(|e| {
+        anyhow::anyhow!(
+            "signal server does not support api v{}, e: {:?}",
+            API_VERSION,
+            e
+        )
+    })?;
+    if !version.supported_api_versions.contains(&API_VERSION) {
+        return Err(anyhow::anyhow!(
+            "signal server does not support api v{}, supported: {:?}",
+            API_VERSION,
+            version.supported_api_versions
+        ));
+    }
 
-    tracing::info!("publish_agent response: {:?}", _response);
+    tracing::info!(
+        "signal server version: {}, api_version: {}",
+        version.version,
+        version.api_version
+    );
+    Ok(())
 }
 
-pub fn query_agent(config: &Configurations, name: &str) -> Vec<Agent> {
+pub async fn publish_agent(config: &Configurations, agent: &Agent) -> Result<()> {
     let url = format!(
-        "{}{}/{}",
-        config.signal_server_url, config.query_agent_url, name
+        "{}{}/{}/{}",
+        config.signal_server_url,
+        config.agents_url,
+        urlencoding::encode(&agent.name),
+        agent.uuid
     );
 
-    let a = ureq::AgentBuilder::new()
-        .try_proxy_from_env(true)
-        .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
-        .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
-
-    if let Ok(response) = a.get(&url).call() {
-        let body = response.into_string().unwrap();
-        return serde_json::from_str(&body).unwrap();
-    }
-
-    return vec![];
+    let body = serde_json::to_string(agent)?;
+    let response = send_with_retry::<IgnoredAny>(
+        || {
+            build_request(config, "PUT", &url)
+                .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
+        },
+        Some(&body),
+    )
+    .await;
+
+    tracing::info!("publish_agent response: {:?}", response);
+    response.map(|_| ())
 }
 
-pub fn delete_agent(config: &Configurations, agent: &Agent) {
+pub async fn query_agent(config: &Configurations, name: &str) -> Result<Vec<Agent>> {
     let url = format!(
         "{}{}/{}",
-        config.signal_server_url, config.delete_agent_url, agent.name
+        config.signal_server_url,
+        config.agents_url,
+        urlencoding::encode(name)
     );
 
-    let a = ureq::AgentBuilder::new()
-        .try_proxy_from_env(true)
-        .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
-        .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
-
-    let _response = a
-        .delete(&url)
-        .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
-        .send_string(&serde_json::to_string(agent).unwrap());
+    send_with_retry(|| build_request(config, "GET", &url), None).await
 }
 
-pub fn publish_client_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) {
+pub async fn delete_agent(config: &Configurations, agent: &Agent) -> Result<()> {
     let url = format!(
-        "{}{}/{}",
-        config.signal_server_url, config.publish_client_sdp_url, uuid
+        "{}{}/{}/{}",
+        config.signal_server_url,
+        config.agents_url,
+        urlencoding::encode(&agent.name),
+        agent.uuid
     );
 
-    let a = ureq::AgentBuilder::new()
-        .try_proxy_from_env(true)
-        .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
-        .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
+    send_with_retry::<IgnoredAny>(|| build_request(config, "DELETE", &url), None)
+        .await
+        .map(|_| ())
+}
 
-    let _response = a
-        .post(&url)
-        .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
-        .send_string(&serde_json::to_string(sdp).unwrap());
+pub async fn publish_client_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) -> Result<()> {
+    // sdps of peers without session support have no v2 route
+    let (method, url) = if sdp.session.is_empty() {
+        (
+            "POST",
+            format!(
+                "{}{}/{}",
+                config.signal_server_url,
+                routes::v1_url(
+                    &config.client_sdps_url,
+                    routes::CLIENT_SDPS_URL,
+                    routes::PUBLISH_CLIENT_SDP_V1_URL
+                ),
+                uuid
+            ),
+        )
+    } else {
+        (
+            "PUT",
+            format!(
+                "{}{}/{}/{}",
+                config.signal_server_url, config.client_sdps_url, uuid, sdp.session
+            ),
+        )
+    };
+
+    let body = serde_json::to_string(sdp)?;
+    let response = send_with_retry::<IgnoredAny>(
+        || {
+            build_request(config, method, &url)
+                .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
+        },
+        Some(&body),
+    )
+    .await;
+
+    response.map(|_| ())
 }
 
-pub fn query_client_sdp(config: &Configurations, uuid: &str) -> Vec<Sdp> {
+pub async fn query_client_sdp(config: &Configurations, uuid: &str) -> Result<Vec<Sdp>> {
     let url = format!(
         "{}{}/{}",
-        config.signal_server_url, config.query_client_sdp_url, uuid
+        config.signal_server_url, config.client_sdps_url, uuid
     );
 
-    let a = ureq::AgentBuilder::new()
-        .try_proxy_from_env(true)
-        .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
-        .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
+    send_with_retry(|| build_request(config, "GET", &url), None).await
+}
 
-    if let Ok(response) = a.get(&url).call() {
-        let body = response.into_string().unwrap();
-        return serde_json::from_str(&body).unwrap();
+pub async fn delete_client_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) -> Result<()> {
+    // the v1 route finds the sdp of a peer without session support by its service
+    if sdp.session.is_empty() {
+        let url = format!(
+            "{}{}/{}",
+            config.signal_server_url,
+            routes::v1_url(
+                &config.client_sdps_url,
+                routes::CLIENT_SDPS_URL,
+                routes::DELETE_CLIENT_SDP_V1_URL
+            ),
+            uuid
+        );
+        let body = serde_json::to_string(sdp)?;
+        return send_with_retry::<IgnoredAny>(
+            || {
+                build_request(config, "POST", &url)
+                    .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
+            },
+            Some(&body),
+        )
+        .await
+        .map(|_| ());
     }
 
-    return vec![];
-}
-
-pub fn delete_client_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) {
     let url = format!(
-        "{}{}/{}",
-        config.signal_server_url, config.delete_client_sdp_url, uuid
+        "{}{}/{}/{}",
+        config.signal_server_url, config.client_sdps_url, uuid, sdp.session
     );
 
-    let a = ureq::AgentBuilder::new()
-        .try_proxy_from_env(true)
-        .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
-        .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
+    send_with_retry::<IgnoredAny>(|| build_request(config, "DELETE", &url), None)
+        .await
+        .map(|_| ())
+}
 
-    let _response = a
-        .post(&url)
-        .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
-        .send_string(&serde_json::to_string(sdp).unwrap());
+pub async fn publish_agent_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) -> Result<()> {
+    // sdps of peers without session support have no v2 route
+    let (method, url) = if sdp.session.is_empty() {
+        (
+            "POST",
+            format!(
+                "{}{}/{}",
+                config.signal_server_url,
+                routes::v1_url(
+                    &config.agent_sdps_url,
+                    routes::AGENT_SDPS_URL,
+                    routes::PUBLISH_AGENT_SDP_V1_URL
+                ),
+                uuid
+            ),
+        )
+    } else {
+        (
+            "PUT",
+            format!(
+                "{}{}/{}/{}",
+                config.signal_server_url, config.agent_sdps_url, uuid, sdp.session
+            ),
+        )
+    };
+
+    let body = serde_json::to_string(sdp)?;
+    let response = send_with_retry::<IgnoredAny>(
+        || {
+            build_request(config, method, &url)
+                .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
+        },
+        Some(&body),
+    )
+    .await;
+
+    response.map(|_| ())
 }
 
-pub fn publish_agent_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) {
+pub async fn query_agent_sdp(config: &Configurations, uuid: &str) -> Result<Vec<Sdp>> {
     let url = format!(
         "{}{}/{}",
-        config.signal_server_url, config.publish_agent_sdp_url, uuid
+        config.signal_server_url, config.agent_sdps_url, uuid
     );
 
-    let a = ureq::AgentBuilder::new()
-        .try_proxy_from_env(true)
-        .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
-        .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
-
-    let _response = a
-        .post(&url)
-        .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
-        .send_string(&serde_json::to_string(sdp).unwrap());
+    send_with_retry(|| build_request(config, "GET", &url), None).await
 }
 
-pub fn query_agent_sdp(config: &Configurations, uuid: &str) -> Vec<Sdp> {
+pub async fn delete_agent_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) -> Result<()> {
+    // the v1 route finds the sdp of a peer without session support by its service
+    if sdp.session.is_empty() {
+        let url = format!(
+            "{}{}/{}",
+            config.signal_server_url,
+            routes::v1_url(
+                &config.agent_sdps_url,
+                routes::AGENT_SDPS_URL,
+                routes::DELETE_AGENT_SDP_V1_URL
+            ),
+            uuid
+        );
+        let body = serde_json::to_string(sdp)?;
+        return send_with_retry::<IgnoredAny>(
+            || {
+                build_request(config, "POST", &url)
+                    .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
+            },
+            Some(&body),
+        )
+        .await
+        .map(|_| ());
+    }
+
     let url = format!(
-        "{}{}/{}",
-        config.signal_server_url, config.query_agent_sdp_url, uuid
+        "{}{}/{}/{}",
+        config.signal_server_url, config.agent_sdps_url, uuid, sdp.session
     );
 
-    let a = ureq::AgentBuilder::new()
-        .try_proxy_from_env(true)
-        .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
-        .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
+    send_with_retry::<IgnoredAny>(|| build_request(config, "DELETE", &url), None)
+        .await
+        .map(|_| ())
+}
 
-    if let Ok(response) = a.get(&url).call() {
-        let body = response.into_string().unwrap();
-        return serde_json::from_str(&body).unwrap();
-    }
+pub async fn admin_list_agents(config: &Configurations) -> Result<Vec<AgentSummary>> {
+    let url = format!("{}{}/agents", config.signal_server_url, config.admin_url);
+
+    send_with_retry(|| build_request(config, "GET", &url), None).await
+}
+
+pub async fn admin_list_sdps(config: &Configurations) -> Result<Vec<SdpSummary>> {
+    let url = format!("{}{}/sdps", config.signal_server_url, config.admin_url);
 
-    return vec![];
+    send_with_retry(|| build_request(config, "GET", &url), None).await
 }
 
-pub fn delete_agent_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) {
+pub async fn admin_query_sweep(config: &Configurations) -> Result<SweepReport> {
+    let url = format!("{}{}/sweep", config.signal_server_url, config.admin_url);
+
+    send_with_retry(|| build_request(config, "GET", &url), None).await
+}
+
+pub async fn admin_expire_agent(config: &Configurations, name: &str, uuid: &str) -> Result<()> {
     let url = format!(
-        "{}{}/{}",
-        config.signal_server_url, config.delete_agent_sdp_url, uuid
+        "{}{}/agents/{}/{}/expire",
+        config.signal_server_url,
+        config.admin_url,
+        urlencoding::encode(name),
+        uuid
     );
 
-    let a = ureq::AgentBuilder::new()
+    send_with_retry::<IgnoredAny>(|| build_request(config, "POST", &url), None)
+        .await
+        .map(|_| ())
+}
+
+pub async fn admin_purge_namespace(
+    config: &Configurations,
+    namespace: &str,
+) -> Result<PurgeResponse> {
+    let url = format!(
+        "{}{}/namespaces/{}",
+        config.signal_server_url,
+        config.admin_url,
+        urlencoding::encode(namespace)
+    );
+
+    send_with_retry(|| build_request(config, "DELETE", &url), None).await
+}
+
+fn build_request(config: &Configurations, method: &str, url: &str) -> ureq::Request {
+    let request = ureq::AgentBuilder::new()
         .try_proxy_from_env(true)
         .timeout_read(std::time::Duration::from_secs(HTTP_READ_TIMEOUT_SECS))
         .timeout_write(std::time::Duration::from_secs(HTTP_WRITE_TIEOUT_SECS))
-        .build();
+        .build()
+        .request(method, url);
+
+    if config.token.is_empty() {
+        request
+    } else {
+        request.set(
+            HTTP_HEADER_KEY_AUTHORIZATION,
+            &format!("Bearer {}", config.token),
+        )
+    }
+}
 
-    let _response = a
-        .post(&url)
-        .set(HTTP_HEADER_KEY_CONTENT_TYPE, HTTP_HEADER_VALUE_APP_JSON)
-        .send_string(&serde_json::to_string(sdp).unwrap());
+// Send a request and deserialize its response, resending rate limited requests
+// after the delay the server asked for
+async fn send_with_retry<T: DeserializeOwned>(
+    build: impl Fn() -> ureq::Request,
+    body: Option<&str>,
+) -> Result<T> {
+    let mut retries = 0;
+    loop {
+        let request = build();
+        let body = body.map(str::to_string);
+        // ureq blocks, so the request and reading its body run off the async workers
+        let response = tokio::task::spawn_blocking(move || {
+            read_response(match body {
+                Some(body) => request.send_string(&body),
+                None => request.call(),
+            })
+        })
+        .await??;
+
+        if response.status == 429 && retries < HTTP_MAX_RETRIES {
+            let retry_after = response
+                .retry_after
+                .unwrap_or(1)
+                .min(HTTP_MAX_RETRY_AFTER_SECS);
+            tracing::warn!("rate limited, retry after {}s", retry_after);
+            tokio::time::sleep(std::time::Duration::from_secs(retry_after)).await;
+            retries += 1;
+            continue;
+        }
+        return parse_response(response);
+    }
+}
+
+struct HttpResponse {
+    status: u16,
+    retry_after: Option<u64>,
+    body: String,
+}
+
+// Status, Retry-After seconds and body of a response, error statuses included
+fn read_response(response: Result<ureq::Response, ureq::Error>) -> Result<HttpResponse> {
+    let response = match response {
+        Ok(response) => response,
+        Err(ureq::Error::Status(_, response)) => response,
+        Err(e) => return Err(e.into()),
+    };
+    let status = response.status();
+    let retry_after = response
+        .header(HTTP_HEADER_KEY_RETRY_AFTER)
+        .and_then(|v| v.trim().parse::<u64>().ok());
+    let body = response.into_string().unwrap_or_default();
+    Ok(HttpResponse {
+        status,
+        retry_after,
+        body,
+    })
+}
+
+// Deserialize a successful body, or turn the worker's error body into an ErrorResponse
+fn parse_response<T: DeserializeOwned>(response: HttpResponse) -> Result<T> {
+    if (200..300).contains(&response.status) {
+        return Ok(serde_json::from_str(&response.body)?);
+    }
+    match serde_json::from_str::<ErrorResponse>(&response.body) {
+        Ok(e) => Err(e.into()),
+        Err(_) => Err(anyhow::anyhow!(
+            "status: {}, body: {}",
+            response.status,
+            response.body
+        )),
+    }
 }
@@ -2,13 +2,12 @@ use std::str::FromStr;
 
 use aes::AesEncryption;
 use anyhow::Result;
-use candidate::IceEndpoint;
 use clap::Parser;
 use data::Configurations;
 use time::{macros::format_description, UtcOffset};
-use tracing;
 use tracing_subscriber::{self, fmt::time::OffsetTime};
 
+mod admin;
 mod aes;
 mod agent;
 mod candidate;
@@ -34,6 +33,7 @@ async fn main() -> Result<()> {
 
     match cmd_args.command {
         command::Commands::Agent {} => agent::process().await?,
+        command::Commands::Admin { command } => admin::process(command).await?,
         command::Commands::Query { name } => client::query::process(&name).await?,
         command::Commands::Connect {
             name,
@@ -44,10 +44,11 @@ async fn main() -> Result<()> {
         } => client::connect::process(&name, &uuid, udp, local_port, remote_port).await?,
         command::Commands::Test { path, name, uuid } => {
             let config = Configurations::load_file(false);
-            if path.starts_with(&config.query_client_sdp_url) {
-                let sdps = http_client::query_client_sdp(&config, &uuid);
+            http_client::handshake(&config).await?;
+            if path.starts_with(&config.client_sdps_url) {
+                let sdps = http_client::query_client_sdp(&config, &uuid).await?;
                 for sdp in sdps {
-                    match AesEncryption::new(&config.password).decrypt(&sdp.sdp.as_slice()) {
+                    match AesEncryption::new(&config.password).decrypt(sdp.sdp.as_slice()) {
                         Err(e) => {
                             tracing::error!(
                                 "AesEncryption::new(&config.password).decrypt() error, e: {:?}",
@@ -56,7 +57,8 @@ async fn main() -> Result<()> {
                         }
                         Ok(text) => {
                             tracing::info!(
-                                "is_udp: {}, port: {}, candidates:\n{}",
+                                "session: {}, is_udp: {}, port: {}, candidates:\n{}",
+                                sdp.session,
                                 sdp.is_udp,
                                 sdp.port,
                                 text
@@ -64,10 +66,10 @@ async fn main() -> Result<()> {
                         }
                     }
                 }
-            } else if path.starts_with(&config.query_agent_sdp_url) {
-                let sdps = http_client::query_agent_sdp(&config, &uuid);
+            } else if path.starts_with(&config.agent_sdps_url) {
+                let sdps = http_client::query_agent_sdp(&config, &uuid).await?;
                 for sdp in sdps {
-                    match AesEncryption::new(&config.password).decrypt(&sdp.sdp.as_slice()) {
+                    match AesEncryption::new(&config.password).decrypt(sdp.sdp.as_slice()) {
                         Err(e) => {
                             tracing::error!(
                                 "AesEncryption::new(&config.password).decrypt() error, e: {:?}",
@@ -76,7 +78,8 @@ async fn main() -> Result<()> {
                         }
                         Ok(text) => {
                             tracing::info!(
-                                "is_udp: {}, port: {}, candidates:\n{}",
+                                "session: {}, is_udp: {}, port: {}, candidates:\n{}",
+                                sdp.session,
                                 sdp.is_udp,
                                 sdp.port,
                                 text
@@ -84,8 +87,8 @@ async fn main() -> Result<()> {
                         }
                     }
                 }
-            } else if path.starts_with(&config.query_agent_url) {
-                let agents = http_client::query_agent(&config, &name);
+            } else if path.starts_with(&config.agents_url) {
+                let agents = http_client::query_agent(&config, &name).await?;
                 tracing::info!("{:?}", agents);
             }
         }
@@ -0,0 +1,210 @@
+use serde::{Deserialize, Serialize};
+
+pub static API_VERSION: u32 = 2;
+pub static SUPPORTED_API_VERSIONS: [u32; 2] = [1, 2];
+// an agent is considered stale after missing this many heartbeats
+pub static HEARTBEAT_STALE_FACTOR: u64 = 3;
+
+// A local port the agent advertises, informational only, clients may still ask for any port
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Service {
+    pub name: String,
+    pub is_udp: bool,
+    pub port: u16,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Agent {
+    pub uuid: String,
+    pub name: String,
+    pub os: String,
+    #[serde(default)]
+    pub version: String,
+    // unix timestamp in seconds of the latest publish, stamped by the worker
+    #[serde(default)]
+    pub last_seen: i64,
+    #[serde(default)]
+    pub services: Vec<Service>,
+}
+
+impl Agent {
+    pub fn is_online(&self, heartbeat_interval_secs: u64) -> bool {
+        let now = std::time::SystemTime::now()
+            .duration_since(std::time::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs() as i64);
+        now - self.last_seen <= (heartbeat_interval_secs * HEARTBEAT_STALE_FACTOR) as i64
+    }
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Sdp {
+    // opaque to the worker, encrypted ice candidates or a json session description
+    pub sdp: Vec<u8>,
+    pub is_udp: bool,
+    pub port: u16,
+    // generated by the client for every connection attempt
+    #[serde(default)]
+    pub session: String,
+    // ice restart generation of the session, an offer with a higher one restarts ice
+    #[serde(default)]
+    pub restart: u32,
+}
+
+// One trickled ice candidate of a session, numbered by its sender so retried puts stay idempotent
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct Candidate {
+    pub session: String,
+    pub index: u32,
+    // opaque to the worker, a json RTCIceCandidateInit, empty in the closing candidate
+    pub candidate: Vec<u8>,
+    // the sender has gathered all its candidates
+    #[serde(default)]
+    pub complete: bool,
+    // ice restart generation of the session description the candidate belongs to
+    #[serde(default)]
+    pub restart: u32,
+}
+
+// Returned by GET /v2/version so clients can check compatibility before use
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct VersionResponse {
+    pub api_version: u32,
+    pub supported_api_versions: Vec<u32>,
+    pub version: String,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct AgentSummary {
+    pub name: String,
+    pub uuid: String,
+    pub os: String,
+    pub version: String,
+    pub last_seen: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct SdpSummary {
+    // "client" or "agent"
+    pub kind: String,
+    pub uuid: String,
+    pub session: String,
+    pub is_udp: bool,
+    pub port: u16,
+    // encrypted sdp length in bytes
+    pub size: usize,
+    // seconds since the sdp was published
+    pub age: i64,
+    pub expires_at: i64,
+}
+
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct PurgeResponse {
+    pub namespace: String,
+    pub deleted: usize,
+}
+
+// Counts of what one cleanup run removed
+#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
+pub struct SweepReport {
+    pub swept_at: i64,
+    pub agents: usize,
+    pub client_sdps: usize,
+    pub agent_sdps: usize,
+    #[serde(default)]
+    pub candidates: usize,
+    pub malformed: usize,
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::de::DeserializeOwned;
+
+    use super::*;
+
+    fn round_trip<T: Serialize + DeserializeOwned + PartialEq + std::fmt::Debug>(value: T) {
+        let text = serde_json::to_string(&value).unwrap();
+        assert_eq!(serde_json::from_str::<T>(&text).unwrap(), value);
+    }
+
+    fn agent() -> Agent {
+        Agent {
+            uuid: "u".to_string(),
+            name: "office".to_string(),
+            os: "linux".to_string(),
+            version: "2025.3.4".to_string(),
+            last_seen: 1,
+            services: vec![Service {
+                name: "ssh".to_string(),
+                is_udp: false,
+                port: 22,
+            }],
+        }
+    }
+
+    #[test]
+    fn types_round_trip() {
+        round_trip(agent());
+        round_trip(Sdp {
+            sdp: vec![0, 1, 255],
+            is_udp: true,
+            port: 53,
+            session: "s".to_string(),
+            restart: 1,
+        });
+        round_trip(Candidate {
+            session: "s".to_string(),
+            index: 3,
+            candidate: b"{}".to_vec(),
+            complete: false,
+            restart: 1,
+        });
+        round_trip(VersionResponse {
+            api_version: API_VERSION,
+            supported_api_versions: SUPPORTED_API_VERSIONS.to_vec(),
+            version: "2025.3.4".to_string(),
+        });
+        round_trip(AgentSummary::default());
+        round_trip(SdpSummary::default());
+        round_trip(PurgeResponse::default());
+        round_trip(SweepReport::default());
+    }
+
+    #[test]
+    fn wire_format_is_stable() {
+        assert_eq!(
+            serde_json::to_value(agent()).unwrap(),
+            serde_json::json!({
+                "uuid": "u",
+                "name": "office",
+                "os": "linux",
+                "version": "2025.3.4",
+                "last_seen": 1,
+                "services": [{"name": "ssh", "is_udp": false, "port": 22}],
+            })
+        );
+        // bytes travel as a json array of numbers, never as a string
+        assert_eq!(
+            serde_json::to_value(Sdp {
+                sdp: b"ab".to_vec(),
+                ..Default::default()
+            })
+            .unwrap(),
+            serde_json::json!({"sdp": [97, 98], "is_udp": false, "port": 0, "session": "", "restart": 0})
+        );
+        assert!(serde_json::from_str::<Sdp>(
+            r#"{"sdp":"text","is_udp":false,"port":22,"session":""}"#
+        )
+        .is_err());
+    }
+
+    #[test]
+    fn older_peers_are_accepted() {
+        let agent: Agent = serde_json::from_str(r#"{"uuid":"u","name":"n","os":"linux"}"#).unwrap();
+        assert_eq!((agent.last_seen, agent.services.len()), (0, 0));
+
+        let sdp: Sdp = serde_json::from_str(r#"{"sdp":[1],"is_udp":false,"port":22}"#).unwrap();
+        assert!(sdp.session.is_empty());
+        assert_eq!(sdp.restart, 0);
+    }
+}
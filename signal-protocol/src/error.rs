@@ -0,0 +1,155 @@
+use serde::{Deserialize, Serialize};
+
+// Stable error codes, matched on by the rust clients
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCode {
+    BadRequest,
+    Unauthorized,
+    Forbidden,
+    NotFound,
+    PayloadTooLarge,
+    TooManyEntries,
+    TooManyRequests,
+    ConfigError,
+    StorageError,
+    // codes added by a newer worker
+    #[serde(other)]
+    Unknown,
+}
+
+impl ErrorCode {
+    pub fn status(&self) -> u16 {
+        match self {
+            ErrorCode::BadRequest => 400,
+            ErrorCode::Unauthorized => 401,
+            ErrorCode::Forbidden => 403,
+            ErrorCode::NotFound => 404,
+            ErrorCode::PayloadTooLarge => 413,
+            ErrorCode::TooManyEntries => 413,
+            ErrorCode::TooManyRequests => 429,
+            ErrorCode::ConfigError => 500,
+            ErrorCode::StorageError => 500,
+            ErrorCode::Unknown => 500,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Deserialize, Serialize, PartialEq)]
+pub struct ErrorResponse {
+    pub code: ErrorCode,
+    pub message: String,
+    // seconds to wait before retrying, also sent as the Retry-After header
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub retry_after: Option<u64>,
+}
+
+impl ErrorResponse {
+    pub fn new(code: ErrorCode, message: impl Into<String>) -> Self {
+        Self {
+            code,
+            message: message.into(),
+            retry_after: None,
+        }
+    }
+
+    pub fn bad_request(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::BadRequest, message)
+    }
+
+    pub fn unauthorized(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Unauthorized, message)
+    }
+
+    pub fn forbidden(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::Forbidden, message)
+    }
+
+    pub fn not_found(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::NotFound, message)
+    }
+
+    pub fn payload_too_large(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::PayloadTooLarge, message)
+    }
+
+    pub fn too_many_entries(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::TooManyEntries, message)
+    }
+
+    pub fn too_many_requests(message: impl Into<String>, retry_after: u64) -> Self {
+        Self {
+            retry_after: Some(retry_after),
+            ..Self::new(ErrorCode::TooManyRequests, message)
+        }
+    }
+
+    pub fn config_error(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::ConfigError, message)
+    }
+
+    pub fn storage_error(message: impl Into<String>) -> Self {
+        Self::new(ErrorCode::StorageError, message)
+    }
+}
+
+impl std::fmt::Display for ErrorResponse {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{:?}: {}", self.code, self.message)
+    }
+}
+
+impl std::error::Error for ErrorResponse {}
+
+#[cfg(feature = "worker")]
+impl ErrorResponse {
+    pub fn to_response(&self) -> worker::Result<worker::Response> {
+        let mut response = worker::Response::from_json(self)?.with_status(self.code.status());
+        if let Some(retry_after) = self.retry_after {
+            response
+                .headers_mut()
+                .set("Retry-After", &retry_after.to_string())?;
+        }
+        Ok(response)
+    }
+}
+
+#[cfg(feature = "worker")]
+impl From<worker::kv::KvError> for ErrorResponse {
+    fn from(e: worker::kv::KvError) -> Self {
+        Self::storage_error(e.to_string())
+    }
+}
+
+#[cfg(feature = "worker")]
+impl From<worker::Error> for ErrorResponse {
+    fn from(e: worker::Error) -> Self {
+        Self::storage_error(e.to_string())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn error_round_trips() {
+        let e = ErrorResponse::too_many_requests("slow down", 60);
+        let text = serde_json::to_string(&e).unwrap();
+        assert_eq!(
+            text,
+            r#"{"code":"too_many_requests","message":"slow down","retry_after":60}"#
+        );
+        assert_eq!(serde_json::from_str::<ErrorResponse>(&text).unwrap(), e);
+
+        let text = serde_json::to_string(&ErrorResponse::not_found("gone")).unwrap();
+        assert_eq!(text, r#"{"code":"not_found","message":"gone"}"#);
+    }
+
+    #[test]
+    fn unknown_codes_are_tolerated() {
+        let e: ErrorResponse =
+            serde_json::from_str(r#"{"code":"teapot","message":"short and stout"}"#).unwrap();
+        assert_eq!(e.code, ErrorCode::Unknown);
+    }
+}
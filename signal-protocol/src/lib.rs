@@ -0,0 +1,8 @@
+pub mod data;
+pub mod error;
+pub mod openapi;
+pub mod routes;
+
+pub use data::*;
+pub use error::{ErrorCode, ErrorResponse};
+pub use routes::Scope;
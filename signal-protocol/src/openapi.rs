@@ -0,0 +1,283 @@
+use serde_json::{json, Map, Value};
+
+use crate::routes::{Body, Route, ROUTES};
+
+// OpenAPI 3.0 description of the v2 signal api, served at routes::OPENAPI
+pub fn document() -> Value {
+    let mut paths = Map::new();
+    for route in ROUTES {
+        let path = paths
+            .entry(openapi_path(route.path))
+            .or_insert_with(|| json!({}));
+        path[route.method.as_str()] = operation(route);
+    }
+
+    json!({
+        "openapi": "3.0.3",
+        "info": {
+            "title": "p2p-proxy signal api",
+            "version": format!("{}", crate::API_VERSION),
+        },
+        "paths": paths,
+        "components": {
+            "securitySchemes": {
+                "bearerAuth": {"type": "http", "scheme": "bearer"},
+            },
+            "schemas": schemas(),
+        },
+    })
+}
+
+// /v2/agents/:name -> /v2/agents/{name}
+fn openapi_path(path: &str) -> String {
+    path.split('/')
+        .map(|segment| match segment.strip_prefix(':') {
+            Some(param) => format!("{{{}}}", param),
+            None => segment.to_string(),
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
+
+fn operation(route: &Route) -> Value {
+    let parameters = route
+        .path
+        .split('/')
+        .filter_map(|segment| segment.strip_prefix(':'))
+        .map(|param| {
+            json!({
+                "name": param,
+                "in": "path",
+                "required": true,
+                "schema": {"type": "string"},
+            })
+        })
+        .collect::<Vec<_>>();
+
+    let mut operation = json!({
+        "summary": route.summary,
+        "parameters": parameters,
+        "responses": {
+            "200": response(&route.response),
+            "default": {
+                "description": "error",
+                "content": {"application/json": {"schema": schema_ref("ErrorResponse")}},
+            },
+        },
+    });
+    if let Some(scope) = route.scope {
+        operation["security"] = json!([{"bearerAuth": []}]);
+        operation["x-scope"] = json!(scope);
+    }
+    if let Some(request) = &route.request {
+        operation["requestBody"] = json!({
+            "required": true,
+            "content": content(request),
+        });
+    }
+    operation
+}
+
+fn response(body: &Body) -> Value {
+    json!({"description": "ok", "content": content(body)})
+}
+
+fn content(body: &Body) -> Value {
+    match body {
+        Body::Empty => json!({"application/json": {"schema": {"type": "object"}}}),
+        Body::Json(name) => json!({"application/json": {"schema": schema_ref(name)}}),
+        Body::JsonArray(name) => {
+            json!({"application/json": {"schema": {"type": "array", "items": schema_ref(name)}}})
+        }
+        Body::Html => json!({"text/html": {"schema": {"type": "string"}}}),
+    }
+}
+
+fn schema_ref(name: &str) -> Value {
+    json!({"$ref": format!("#/components/schemas/{}", name)})
+}
+
+fn object(required: &[&str], properties: Value) -> Value {
+    json!({"type": "object", "required": required, "properties": properties})
+}
+
+fn schemas() -> Value {
+    let string = json!({"type": "string"});
+    let boolean = json!({"type": "boolean"});
+    let port = json!({"type": "integer", "minimum": 0, "maximum": 65535});
+    let count = json!({"type": "integer", "minimum": 0});
+    let timestamp = json!({"type": "integer", "format": "int64"});
+
+    json!({
+        "Service": object(&["name", "is_udp", "port"], json!({
+            "name": string,
+            "is_udp": boolean,
+            "port": port,
+        })),
+        "Agent": object(&["uuid", "name", "os"], json!({
+            "uuid": string,
+            "name": string,
+            "os": string,
+            "version": string,
+            "last_seen": timestamp,
+            "services": {"type": "array", "items": schema_ref("Service")},
+        })),
+        "Sdp": object(&["sdp", "is_udp", "port"], json!({
+            "sdp": {"type": "array", "items": {"type": "integer", "minimum": 0, "maximum": 255}},
+            "is_udp": boolean,
+            "port": port,
+            "session": string,
+            "restart": count,
+        })),
+        "Candidate": object(&["session", "index", "candidate"], json!({
+            "session": string,
+            "index": count,
+            "candidate": {"type": "array", "items": {"type": "integer", "minimum": 0, "maximum": 255}},
+            "complete": boolean,
+            "restart": count,
+        })),
+        "VersionResponse": object(&["api_version", "supported_api_versions", "version"], json!({
+            "api_version": count,
+            "supported_api_versions": {"type": "array", "items": count},
+            "version": string,
+        })),
+        "AgentSummary": object(&["name", "uuid", "os", "version", "last_seen", "expires_at"], json!({
+            "name": string,
+            "uuid": string,
+            "os": string,
+            "version": string,
+            "last_seen": timestamp,
+            "expires_at": timestamp,
+        })),
+        "SdpSummary": object(&["kind", "uuid", "session", "is_udp", "port", "size", "age", "expires_at"], json!({
+            "kind": {"type": "string", "enum": ["client", "agent"]},
+            "uuid": string,
+            "session": string,
+            "is_udp": boolean,
+            "port": port,
+            "size": count,
+            "age": timestamp,
+            "expires_at": timestamp,
+        })),
+        "PurgeResponse": object(&["namespace", "deleted"], json!({
+            "namespace": string,
+            "deleted": count,
+        })),
+        "SweepReport": object(&["swept_at", "agents", "client_sdps", "agent_sdps", "malformed"], json!({
+            "swept_at": timestamp,
+            "agents": count,
+            "client_sdps": count,
+            "agent_sdps": count,
+            "candidates": count,
+            "malformed": count,
+        })),
+        "ErrorResponse": object(&["code", "message"], json!({
+            "code": {"type": "string", "enum": [
+                "bad_request",
+                "unauthorized",
+                "forbidden",
+                "not_found",
+                "payload_too_large",
+                "too_many_entries",
+                "too_many_requests",
+                "config_error",
+                "storage_error",
+            ]},
+            "message": string,
+            "retry_after": count,
+        })),
+        "OpenApi": {"type": "object"},
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use serde::Serialize;
+
+    use super::*;
+    use crate::*;
+
+    fn property_keys(document: &Value, name: &str) -> Vec<String> {
+        let mut keys = document["components"]["schemas"][name]["properties"]
+            .as_object()
+            .unwrap_or_else(|| panic!("schema {} is missing", name))
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    fn field_keys(value: impl Serialize) -> Vec<String> {
+        let mut keys = serde_json::to_value(value)
+            .unwrap()
+            .as_object()
+            .unwrap()
+            .keys()
+            .cloned()
+            .collect::<Vec<_>>();
+        keys.sort();
+        keys
+    }
+
+    #[test]
+    fn schemas_match_the_types() {
+        let document = document();
+        for (name, keys) in [
+            ("Service", field_keys(Service::default())),
+            ("Agent", field_keys(Agent::default())),
+            ("Sdp", field_keys(Sdp::default())),
+            ("Candidate", field_keys(Candidate::default())),
+            ("VersionResponse", field_keys(VersionResponse::default())),
+            ("AgentSummary", field_keys(AgentSummary::default())),
+            ("SdpSummary", field_keys(SdpSummary::default())),
+            ("PurgeResponse", field_keys(PurgeResponse::default())),
+            ("SweepReport", field_keys(SweepReport::default())),
+            (
+                "ErrorResponse",
+                field_keys(ErrorResponse::too_many_requests("", 1)),
+            ),
+        ] {
+            assert_eq!(property_keys(&document, name), keys, "schema {}", name);
+        }
+
+        let codes = document["components"]["schemas"]["ErrorResponse"]["properties"]["code"]
+            ["enum"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|code| serde_json::from_value::<ErrorCode>(code.clone()).unwrap())
+            .collect::<Vec<_>>();
+        assert!(!codes.contains(&ErrorCode::Unknown));
+    }
+
+    #[test]
+    fn every_referenced_schema_exists() {
+        let document = document();
+        let text = document.to_string();
+        for reference in text.split("\"#/components/schemas/").skip(1) {
+            let name = &reference[..reference.find('"').unwrap()];
+            assert!(
+                document["components"]["schemas"][name].is_object(),
+                "schema {} is missing",
+                name
+            );
+        }
+    }
+
+    // Regenerate with `UPDATE_OPENAPI=1 cargo test -p signal-protocol`
+    #[test]
+    fn checked_in_document_is_current() {
+        let path = concat!(env!("CARGO_MANIFEST_DIR"), "/openapi.json");
+        let generated = serde_json::to_string_pretty(&document()).unwrap() + "\n";
+        if std::env::var("UPDATE_OPENAPI").is_ok() {
+            std::fs::write(path, &generated).unwrap();
+        }
+        let checked_in = std::fs::read_to_string(path).unwrap_or_default();
+        assert!(
+            checked_in == generated,
+            "{} is outdated, run with UPDATE_OPENAPI=1",
+            path
+        );
+    }
+}
@@ -0,0 +1,357 @@
+use serde::{Deserialize, Serialize};
+
+// Base urls the clients append path segments to, defaults of their *_url settings
+pub static VERSION_URL: &str = "/v2/version";
+pub static AGENTS_URL: &str = "/v2/agents";
+pub static CLIENT_SDPS_URL: &str = "/v2/client-sdps";
+pub static AGENT_SDPS_URL: &str = "/v2/agent-sdps";
+pub static CLIENT_CANDIDATES_URL: &str = "/v2/client-candidates";
+pub static AGENT_CANDIDATES_URL: &str = "/v2/agent-candidates";
+pub static ADMIN_URL: &str = "/v2/admin";
+
+// v1 urls, still served for sdps without a session, which have no v2 route
+pub static PUBLISH_CLIENT_SDP_V1_URL: &str = "/publish/client/sdp";
+pub static DELETE_CLIENT_SDP_V1_URL: &str = "/delete/client/sdp";
+pub static PUBLISH_AGENT_SDP_V1_URL: &str = "/publish/agent/sdp";
+pub static DELETE_AGENT_SDP_V1_URL: &str = "/delete/agent/sdp";
+// defaults of the v1 *_url settings the v2 base urls replaced
+pub static V1_URLS: &[&str] = &[
+    "/publish/agent",
+    "/query/agent",
+    "/delete/agent",
+    PUBLISH_CLIENT_SDP_V1_URL,
+    "/query/client/sdp",
+    DELETE_CLIENT_SDP_V1_URL,
+    PUBLISH_AGENT_SDP_V1_URL,
+    "/query/agent/sdp",
+    DELETE_AGENT_SDP_V1_URL,
+];
+
+// Path prefix in front of a v1 *_url setting, e.g. /signal of /signal/publish/agent
+// None when the setting does not end with a v1 url
+pub fn v1_url_prefix(url: &str) -> Option<&str> {
+    V1_URLS.iter().find_map(|v1_url| url.strip_suffix(v1_url))
+}
+
+// v1 url under the path prefix of a v2 *_url setting, e.g. /signal/publish/agent/sdp for
+// /signal/v2/agent-sdps, no prefix when the setting does not end with its v2 base url
+pub fn v1_url(v2_url: &str, v2_base_url: &str, v1_url: &str) -> String {
+    let prefix = v2_url.strip_suffix(v2_base_url).unwrap_or_default();
+    format!("{prefix}{v1_url}")
+}
+
+// Route patterns registered by the worker, :param segments as in worker::Router
+pub static VERSION: &str = "/v2/version";
+pub static OPENAPI: &str = "/v2/openapi.json";
+pub static AGENTS: &str = "/v2/agents/:name";
+pub static AGENT: &str = "/v2/agents/:name/:uuid";
+pub static CLIENT_SDPS: &str = "/v2/client-sdps/:uuid";
+pub static CLIENT_SDP: &str = "/v2/client-sdps/:uuid/:session";
+pub static AGENT_SDPS: &str = "/v2/agent-sdps/:uuid";
+pub static AGENT_SDP: &str = "/v2/agent-sdps/:uuid/:session";
+pub static CLIENT_CANDIDATES: &str = "/v2/client-candidates/:uuid/:session";
+pub static CLIENT_CANDIDATE: &str = "/v2/client-candidates/:uuid/:session/:index";
+pub static AGENT_CANDIDATES: &str = "/v2/agent-candidates/:uuid/:session";
+pub static AGENT_CANDIDATE: &str = "/v2/agent-candidates/:uuid/:session/:index";
+pub static ADMIN_AGENTS: &str = "/v2/admin/agents";
+pub static ADMIN_AGENT_EXPIRE: &str = "/v2/admin/agents/:name/:uuid/expire";
+pub static ADMIN_SDPS: &str = "/v2/admin/sdps";
+pub static ADMIN_SWEEP: &str = "/v2/admin/sweep";
+pub static ADMIN_NAMESPACE: &str = "/v2/admin/namespaces/:namespace";
+pub static DASHBOARD: &str = "/dashboard";
+
+#[derive(Clone, Copy, Debug, Deserialize, Serialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum Scope {
+    // publish/delete itself, read client sdps, publish agent sdps
+    Agent,
+    // query agents, publish/delete client sdps, read agent sdps
+    Client,
+    // everything
+    Admin,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Method {
+    Get,
+    Put,
+    Post,
+    Delete,
+}
+
+impl Method {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Method::Get => "get",
+            Method::Put => "put",
+            Method::Post => "post",
+            Method::Delete => "delete",
+        }
+    }
+}
+
+// Request or response payload of a route, json bodies name a schema of the openapi document
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Body {
+    // an empty json object
+    Empty,
+    Json(&'static str),
+    JsonArray(&'static str),
+    Html,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Route {
+    pub method: Method,
+    pub path: &'static str,
+    // token scope required, None for public routes
+    pub scope: Option<Scope>,
+    pub summary: &'static str,
+    pub request: Option<Body>,
+    pub response: Body,
+}
+
+// Every v2 route of the signal service, the source of the openapi document
+pub static ROUTES: &[Route] = &[
+    Route {
+        method: Method::Get,
+        path: VERSION,
+        scope: None,
+        summary: "api version handshake",
+        request: None,
+        response: Body::Json("VersionResponse"),
+    },
+    Route {
+        method: Method::Get,
+        path: OPENAPI,
+        scope: None,
+        summary: "this openapi document",
+        request: None,
+        response: Body::Json("OpenApi"),
+    },
+    Route {
+        method: Method::Put,
+        path: AGENT,
+        scope: Some(Scope::Agent),
+        summary: "publish agent info, doubles as heartbeat",
+        request: Some(Body::Json("Agent")),
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Get,
+        path: AGENTS,
+        scope: Some(Scope::Client),
+        summary: "query agents by name",
+        request: None,
+        response: Body::JsonArray("Agent"),
+    },
+    Route {
+        method: Method::Delete,
+        path: AGENT,
+        scope: Some(Scope::Agent),
+        summary: "delete agent info",
+        request: None,
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Put,
+        path: CLIENT_SDP,
+        scope: Some(Scope::Client),
+        summary: "publish client sdp",
+        request: Some(Body::Json("Sdp")),
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Get,
+        path: CLIENT_SDPS,
+        scope: Some(Scope::Agent),
+        summary: "query client sdps addressed to an agent",
+        request: None,
+        response: Body::JsonArray("Sdp"),
+    },
+    Route {
+        method: Method::Delete,
+        path: CLIENT_SDP,
+        scope: Some(Scope::Client),
+        summary: "delete client sdp",
+        request: None,
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Put,
+        path: AGENT_SDP,
+        scope: Some(Scope::Agent),
+        summary: "publish agent sdp",
+        request: Some(Body::Json("Sdp")),
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Get,
+        path: AGENT_SDPS,
+        scope: Some(Scope::Client),
+        summary: "query agent sdps of an agent",
+        request: None,
+        response: Body::JsonArray("Sdp"),
+    },
+    Route {
+        method: Method::Delete,
+        path: AGENT_SDP,
+        scope: Some(Scope::Agent),
+        summary: "delete agent sdp",
+        request: None,
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Put,
+        path: CLIENT_CANDIDATE,
+        scope: Some(Scope::Client),
+        summary: "publish a trickled client ice candidate",
+        request: Some(Body::Json("Candidate")),
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Get,
+        path: CLIENT_CANDIDATES,
+        scope: Some(Scope::Agent),
+        summary: "query client ice candidates of a session",
+        request: None,
+        response: Body::JsonArray("Candidate"),
+    },
+    Route {
+        method: Method::Put,
+        path: AGENT_CANDIDATE,
+        scope: Some(Scope::Agent),
+        summary: "publish a trickled agent ice candidate",
+        request: Some(Body::Json("Candidate")),
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Get,
+        path: AGENT_CANDIDATES,
+        scope: Some(Scope::Client),
+        summary: "query agent ice candidates of a session",
+        request: None,
+        response: Body::JsonArray("Candidate"),
+    },
+    Route {
+        method: Method::Get,
+        path: ADMIN_AGENTS,
+        scope: Some(Scope::Admin),
+        summary: "list agents with last seen and expiry",
+        request: None,
+        response: Body::JsonArray("AgentSummary"),
+    },
+    Route {
+        method: Method::Post,
+        path: ADMIN_AGENT_EXPIRE,
+        scope: Some(Scope::Admin),
+        summary: "force-expire an agent",
+        request: None,
+        response: Body::Empty,
+    },
+    Route {
+        method: Method::Get,
+        path: ADMIN_SDPS,
+        scope: Some(Scope::Admin),
+        summary: "list pending sdps with sizes and ages",
+        request: None,
+        response: Body::JsonArray("SdpSummary"),
+    },
+    Route {
+        method: Method::Get,
+        path: ADMIN_SWEEP,
+        scope: Some(Scope::Admin),
+        summary: "counts removed from the namespace by the latest scheduled cleanup",
+        request: None,
+        response: Body::Json("SweepReport"),
+    },
+    Route {
+        method: Method::Delete,
+        path: ADMIN_NAMESPACE,
+        scope: Some(Scope::Admin),
+        summary: "purge the namespace of the token",
+        request: None,
+        response: Body::Json("PurgeResponse"),
+    },
+    Route {
+        method: Method::Get,
+        path: DASHBOARD,
+        scope: Some(Scope::Admin),
+        summary:
+            "html page of agents and connection requests, ?token= is traded for a session cookie",
+        request: None,
+        response: Body::Html,
+    },
+];
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn client_urls_prefix_their_routes() {
+        for (url, route) in [
+            (VERSION_URL, VERSION),
+            (AGENTS_URL, AGENTS),
+            (AGENTS_URL, AGENT),
+            (CLIENT_SDPS_URL, CLIENT_SDPS),
+            (CLIENT_SDPS_URL, CLIENT_SDP),
+            (AGENT_SDPS_URL, AGENT_SDPS),
+            (AGENT_SDPS_URL, AGENT_SDP),
+            (CLIENT_CANDIDATES_URL, CLIENT_CANDIDATES),
+            (CLIENT_CANDIDATES_URL, CLIENT_CANDIDATE),
+            (AGENT_CANDIDATES_URL, AGENT_CANDIDATES),
+            (AGENT_CANDIDATES_URL, AGENT_CANDIDATE),
+            (ADMIN_URL, ADMIN_AGENTS),
+            (ADMIN_URL, ADMIN_AGENT_EXPIRE),
+            (ADMIN_URL, ADMIN_SDPS),
+            (ADMIN_URL, ADMIN_SWEEP),
+            (ADMIN_URL, ADMIN_NAMESPACE),
+        ] {
+            assert!(route.starts_with(url), "{route} does not start with {url}");
+        }
+    }
+
+    #[test]
+    fn v1_url_prefix_strips_only_v1_urls() {
+        assert_eq!(v1_url_prefix("/publish/agent"), Some(""));
+        assert_eq!(v1_url_prefix("/signal/query/agent/sdp"), Some("/signal"));
+        assert_eq!(v1_url_prefix("/signal/delete/agent"), Some("/signal"));
+        assert_eq!(v1_url_prefix(AGENTS_URL), None);
+        assert_eq!(v1_url_prefix(""), None);
+    }
+
+    #[test]
+    fn v1_url_keeps_the_prefix_of_the_v2_url() {
+        assert_eq!(
+            v1_url(AGENT_SDPS_URL, AGENT_SDPS_URL, PUBLISH_AGENT_SDP_V1_URL),
+            "/publish/agent/sdp"
+        );
+        assert_eq!(
+            v1_url(
+                "/signal/v2/client-sdps",
+                CLIENT_SDPS_URL,
+                DELETE_CLIENT_SDP_V1_URL
+            ),
+            "/signal/delete/client/sdp"
+        );
+        assert_eq!(
+            v1_url("/custom/sdps", AGENT_SDPS_URL, PUBLISH_AGENT_SDP_V1_URL),
+            "/publish/agent/sdp"
+        );
+    }
+
+    #[test]
+    fn routes_are_unique() {
+        for (i, a) in ROUTES.iter().enumerate() {
+            for b in &ROUTES[i + 1..] {
+                assert!(
+                    (a.method, a.path) != (b.method, b.path),
+                    "{:?} {} is listed twice",
+                    a.method,
+                    a.path
+                );
+            }
+        }
+    }
+}
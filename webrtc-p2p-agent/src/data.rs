@@ -1,8 +1,13 @@
+use std::collections::BTreeMap;
+
 use config_file_derives::ConfigFile;
 use config_file_types;
 use serde::{Deserialize, Serialize};
+use signal_protocol::routes;
+use webrtc_p2p_common::RtcConfigurations;
 
 static CONFIG_PATH: &str = "agent.json";
+static DEFAULT_HEARTBEAT_INTERVAL_SECS: u64 = 60;
 
 #[derive(Debug, Default, Serialize, Deserialize, ConfigFile)]
 #[config_file_ext("json")]
@@ -14,12 +19,38 @@ pub struct Configurations {
     pub name: String,
     pub os: String,
 
-    pub stun_server_urls: Vec<String>,
+    // ice servers, candidate gathering and tunnel options
+    #[serde(flatten)]
+    pub rtc: RtcConfigurations,
+
+    // tcp ports served by a unix domain socket path instead of 127.0.0.1
+    #[serde(default)]
+    pub unix_sockets: BTreeMap<u16, String>,
+
+    #[serde(default)]
+    pub heartbeat_interval_secs: u64,
 
     pub signal_server_url: String,
-    pub publish_agent_url: String,
-    pub query_client_sdp_url: String,
-    pub publish_agent_sdp_url: String,
+    // bearer token sent to the signal server, empty when it runs without auth
+    #[serde(default)]
+    pub token: String,
+    #[serde(default)]
+    pub agents_url: String,
+    #[serde(default)]
+    pub client_sdps_url: String,
+    #[serde(default)]
+    pub agent_sdps_url: String,
+    #[serde(default)]
+    pub client_candidates_url: String,
+    #[serde(default)]
+    pub agent_candidates_url: String,
+    // v1 urls superseded by the v2 urls above, a path prefix in front of them moves there
+    #[serde(default, skip_serializing)]
+    publish_agent_url: String,
+    #[serde(default, skip_serializing)]
+    query_client_sdp_url: String,
+    #[serde(default, skip_serializing)]
+    publish_agent_sdp_url: String,
 }
 
 impl Configurations {
@@ -41,23 +72,50 @@ impl Configurations {
             config.os = std::env::consts::OS.to_string();
             update = true;
         }
-        if config.stun_server_urls.is_empty() {
-            config.stun_server_urls = vec!["stun:stun.l.google.com:19302".to_owned()];
+        if config.rtc.load_defaults() {
+            update = true;
+        }
+        if config.heartbeat_interval_secs == 0 {
+            config.heartbeat_interval_secs = DEFAULT_HEARTBEAT_INTERVAL_SECS;
             update = true;
         }
         if config.signal_server_url.is_empty() {
             tracing::error!("config.signal_server_url.is_empty()");
         }
-        if config.publish_agent_url.is_empty() {
-            config.publish_agent_url = String::from("/publish/agent");
+        let v1_urls = [
+            &config.publish_agent_url,
+            &config.query_client_sdp_url,
+            &config.publish_agent_sdp_url,
+        ];
+        let prefix = v1_urls
+            .iter()
+            .find_map(|url| routes::v1_url_prefix(url))
+            .unwrap_or_default()
+            .to_string();
+        if let Some(url) = v1_urls
+            .iter()
+            .find(|url| !url.is_empty() && routes::v1_url_prefix(url).is_none())
+        {
+            tracing::warn!("v1 url {} can not be migrated to a v2 url", url);
+        }
+        if config.agents_url.is_empty() {
+            config.agents_url = format!("{}{}", prefix, routes::AGENTS_URL);
+            update = true;
+        }
+        if config.client_sdps_url.is_empty() {
+            config.client_sdps_url = format!("{}{}", prefix, routes::CLIENT_SDPS_URL);
             update = true;
         }
-        if config.query_client_sdp_url.is_empty() {
-            config.query_client_sdp_url = String::from("/query/client/sdp");
+        if config.agent_sdps_url.is_empty() {
+            config.agent_sdps_url = format!("{}{}", prefix, routes::AGENT_SDPS_URL);
             update = true;
         }
-        if config.publish_agent_sdp_url.is_empty() {
-            config.publish_agent_sdp_url = String::from("/publish/agent/sdp");
+        if config.client_candidates_url.is_empty() {
+            config.client_candidates_url = format!("{}{}", prefix, routes::CLIENT_CANDIDATES_URL);
+            update = true;
+        }
+        if config.agent_candidates_url.is_empty() {
+            config.agent_candidates_url = format!("{}{}", prefix, routes::AGENT_CANDIDATES_URL);
             update = true;
         }
         if update {
@@ -66,17 +124,3 @@ impl Configurations {
         config
     }
 }
-
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
-pub struct Agent {
-    pub uuid: String,
-    pub name: String,
-    pub os: String,
-}
-
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
-pub struct Sdp {
-    pub sdp: String,
-    pub is_udp: bool,
-    pub port: u16,
-}
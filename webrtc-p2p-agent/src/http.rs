@@ -1,12 +1,20 @@
 use serde_json;
 use ureq;
 
-use crate::data::{Agent, Configurations, Sdp};
+use signal_protocol::{routes, Agent, Candidate, Sdp};
 
-pub fn publish_agent(config: &Configurations, agent: &Agent) {
+use crate::data::Configurations;
+
+static MAX_RETRIES: u32 = 3;
+static MAX_RETRY_AFTER_SECS: u64 = 60;
+
+pub async fn publish_agent(config: &Configurations, agent: &Agent) {
     let url = format!(
-        "{}{}/{}",
-        config.signal_server_url, config.publish_agent_url, agent.name
+        "{}{}/{}/{}",
+        config.signal_server_url,
+        config.agents_url,
+        urlencoding::encode(&agent.name),
+        agent.uuid
     );
 
     let a = ureq::AgentBuilder::new()
@@ -15,16 +23,14 @@ pub fn publish_agent(config: &Configurations, agent: &Agent) {
         .timeout_write(std::time::Duration::from_secs(5))
         .build();
 
-    let _response = a
-        .post(&url)
-        .set("Content-Type", "application/json")
-        .send_string(&serde_json::to_string(agent).unwrap());
+    let body = serde_json::to_string(agent).unwrap();
+    let _response = send(config, || a.put(&url), Some(&body)).await;
 }
 
-pub fn query_client_sdp(config: &Configurations, uuid: &str) -> Vec<Sdp> {
+pub async fn query_client_sdp(config: &Configurations, uuid: &str) -> Vec<Sdp> {
     let url = format!(
         "{}{}/{}",
-        config.signal_server_url, config.query_client_sdp_url, uuid
+        config.signal_server_url, config.client_sdps_url, uuid
     );
 
     let a = ureq::AgentBuilder::new()
@@ -33,18 +39,51 @@ pub fn query_client_sdp(config: &Configurations, uuid: &str) -> Vec<Sdp> {
         .timeout_write(std::time::Duration::from_secs(5))
         .build();
 
-    if let Ok(response) = a.get(&url).call() {
-        let body = response.into_string().unwrap();
+    if let Some(body) = send(config, || a.get(&url), None).await {
         return serde_json::from_str(&body).unwrap();
     }
 
-    return vec![];
+    vec![]
+}
+
+pub async fn publish_agent_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) {
+    let a = ureq::AgentBuilder::new()
+        .try_proxy_from_env(true)
+        .timeout_read(std::time::Duration::from_secs(15))
+        .timeout_write(std::time::Duration::from_secs(5))
+        .build();
+
+    // answers to clients without session support have no v2 route
+    let body = serde_json::to_string(sdp).unwrap();
+    let _response = if sdp.session.is_empty() {
+        let url = format!(
+            "{}{}/{}",
+            config.signal_server_url,
+            routes::v1_url(
+                &config.agent_sdps_url,
+                routes::AGENT_SDPS_URL,
+                routes::PUBLISH_AGENT_SDP_V1_URL
+            ),
+            uuid
+        );
+        send(config, || a.post(&url), Some(&body)).await
+    } else {
+        let url = format!(
+            "{}{}/{}/{}",
+            config.signal_server_url, config.agent_sdps_url, uuid, sdp.session
+        );
+        send(config, || a.put(&url), Some(&body)).await
+    };
 }
 
-pub fn publish_agent_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) {
+pub async fn publish_agent_candidate(config: &Configurations, uuid: &str, candidate: &Candidate) {
     let url = format!(
-        "{}{}/{}",
-        config.signal_server_url, config.publish_agent_sdp_url, uuid
+        "{}{}/{}/{}/{}",
+        config.signal_server_url,
+        config.agent_candidates_url,
+        uuid,
+        candidate.session,
+        candidate.index
     );
 
     let a = ureq::AgentBuilder::new()
@@ -53,8 +92,88 @@ pub fn publish_agent_sdp(config: &Configurations, uuid: &str, sdp: &Sdp) {
         .timeout_write(std::time::Duration::from_secs(5))
         .build();
 
-    let _response = a
-        .post(&url)
-        .set("Content-Type", "application/json")
-        .send_string(&serde_json::to_string(sdp).unwrap());
+    let body = serde_json::to_string(candidate).unwrap();
+    let _response = send(config, || a.put(&url), Some(&body)).await;
+}
+
+pub async fn query_client_candidates(
+    config: &Configurations,
+    uuid: &str,
+    session: &str,
+) -> Vec<Candidate> {
+    let url = format!(
+        "{}{}/{}/{}",
+        config.signal_server_url, config.client_candidates_url, uuid, session
+    );
+
+    let a = ureq::AgentBuilder::new()
+        .try_proxy_from_env(true)
+        .timeout_read(std::time::Duration::from_secs(15))
+        .timeout_write(std::time::Duration::from_secs(5))
+        .build();
+
+    if let Some(body) = send(config, || a.get(&url), None).await {
+        return serde_json::from_str(&body).unwrap();
+    }
+
+    vec![]
+}
+
+// Authorize and send a request, resending it after the delay a rate limited response asked for
+// the body of a successful response, None for errors and error statuses
+async fn send(
+    config: &Configurations,
+    build: impl Fn() -> ureq::Request,
+    body: Option<&str>,
+) -> Option<String> {
+    let mut retries = 0;
+    loop {
+        let request = authorize(config, build());
+        let body = body.map(str::to_string);
+        // ureq blocks, so the request and reading its body run off the async workers
+        let reply = tokio::task::spawn_blocking(move || {
+            let response = match body {
+                Some(body) => request
+                    .set("Content-Type", "application/json")
+                    .send_string(&body),
+                None => request.call(),
+            };
+            match response {
+                Ok(response) => Reply::Body(response.into_string().ok()),
+                Err(ureq::Error::Status(429, response)) => Reply::RateLimited(
+                    response
+                        .header("Retry-After")
+                        .and_then(|v| v.trim().parse::<u64>().ok()),
+                ),
+                Err(_) => Reply::Body(None),
+            }
+        })
+        .await
+        .ok()?;
+
+        match reply {
+            Reply::RateLimited(retry_after) if retries < MAX_RETRIES => {
+                let retry_after = retry_after.unwrap_or(1).min(MAX_RETRY_AFTER_SECS);
+                tracing::warn!("rate limited, retry after {}s", retry_after);
+                tokio::time::sleep(std::time::Duration::from_secs(retry_after)).await;
+                retries += 1;
+            }
+            Reply::RateLimited(_) => return None,
+            Reply::Body(body) => return body,
+        }
+    }
+}
+
+enum Reply {
+    Body(Option<String>),
+    // Retry-After seconds, if the response had them
+    RateLimited(Option<u64>),
+}
+
+fn authorize(config: &Configurations, request: ureq::Request) -> ureq::Request {
+    if config.token.is_empty() {
+        request
+    } else {
+        request.set("Authorization", &format!("Bearer {}", config.token))
+    }
 }
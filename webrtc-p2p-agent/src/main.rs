@@ -1,12 +1,14 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use time::{macros::format_description, UtcOffset};
 
-use tracing;
 use tracing_subscriber::{self, fmt::time::OffsetTime};
 
 pub mod data;
 pub mod http;
 pub mod rtc;
+pub mod session;
 pub mod socket;
 pub mod utils;
 
@@ -26,35 +28,43 @@ async fn main() -> Result<()> {
     if config.signal_server_url.is_empty() {
         return Err(anyhow::anyhow!("invalid config, missing signal_server_url"));
     }
+    config.rtc.check()?;
 
-    http::publish_agent(
-        &config,
-        &data::Agent {
-            uuid: config.uuid.clone(),
-            name: config.name.clone(),
-            os: config.os.clone(),
-        },
-    );
+    let agent = signal_protocol::Agent {
+        uuid: config.uuid.clone(),
+        name: config.name.clone(),
+        os: config.os.clone(),
+        version: env!("CARGO_PKG_VERSION").to_string(),
+        ..Default::default()
+    };
+    http::publish_agent(&config, &agent).await;
+    let mut last_heartbeat = std::time::Instant::now();
 
+    // keep polling for client sdps, one peer connection per new session
+    let config = Arc::new(config);
+    let mut sessions = session::SessionTable::default();
     loop {
-        let mut got = false;
-        for client_sdp in http::query_client_sdp(&config, &config.uuid) {
-            let (mut passive_done_tx, mut passive_done_rx) = tokio::sync::mpsc::channel(1);
-            rtc::start_rtc_agent(&config, client_sdp, &mut passive_done_rx).await?;
-            got = true;
-        }
-        if got {
-            break;
-        } else {
-            tokio::time::sleep(std::time::Duration::from_secs(1)).await;
+        // refresh agent presence
+        if last_heartbeat.elapsed()
+            >= std::time::Duration::from_secs(config.heartbeat_interval_secs)
+        {
+            http::publish_agent(&config, &agent).await;
+            last_heartbeat = std::time::Instant::now();
         }
+
+        sessions.update(&config, http::query_client_sdp(&config, &config.uuid).await);
+
+        tokio::select! {
+            _ = tokio::signal::ctrl_c() => {
+                println!();
+                break;
+            }
+            _ = tokio::time::sleep(std::time::Duration::from_secs(1)) => {}
+        };
     }
 
-    tokio::select! {
-        _ = tokio::signal::ctrl_c() => {
-            println!();
-        }
-    };
+    tracing::info!("closing {} sessions", sessions.len());
+    sessions.shutdown().await;
 
     Ok(())
 }
@@ -1,31 +1,46 @@
-use std::io::Read;
-use std::net::{IpAddr, Ipv4Addr, SocketAddr};
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 
 use anyhow::Result;
-use bytes::Bytes;
-use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use bytes::{Bytes, BytesMut};
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
+use tokio::time::Instant;
 use tracing;
 use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
-use webrtc::api::setting_engine::SettingEngine;
 use webrtc::api::APIBuilder;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
 use webrtc::data_channel::RTCDataChannel;
-use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
 use webrtc::interceptor::registry::Registry;
-use webrtc::peer_connection::configuration::RTCConfiguration;
 use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
 
-use crate::data::{Configurations, Sdp};
+use signal_protocol::Sdp;
+use webrtc_p2p_common::rtc::{
+    add_remote_candidates, rtc_configuration, setting_engine, to_candidate,
+};
+use webrtc_p2p_common::tunnel::{
+    Backpressure, CloseReason, Idle, Traffic, MAX_MESSAGE_SIZE, UDP_IDLE_TIMEOUT, UDP_MESSAGE_SIZE,
+};
+
+use crate::data::Configurations;
 use crate::http;
+use crate::socket::{self, ProxySocket};
 
-const MESSAGE_SIZE: usize = 1500;
+// protocol of the data channel the client negotiates the connection with, it carries no traffic
+const CONTROL_PROTOCOL: &str = "control";
+// time a session has to connect after its answer, a disconnect or an ice restart offer,
+// well above the 30s the client gives each ice restart
+const RECONNECT_TIMEOUT: Duration = Duration::from_secs(90);
 
 pub async fn start_rtc_agent(
-    config: &Configurations,
+    config: Arc<Configurations>,
     client_sdp: Sdp,
+    offer_rx: &mut tokio::sync::mpsc::Receiver<Sdp>,
     passive_done_rx: &mut tokio::sync::mpsc::Receiver<()>,
 ) -> Result<()> {
     // Create a MediaEngine object to configure the supported codec
@@ -43,9 +58,8 @@ pub async fn start_rtc_agent(
     // Use the default set of Interceptors
     registry = register_default_interceptors(registry, &mut media_engine)?;
 
-    // Create a SettingEngine and enable Detach
-    let mut s = SettingEngine::default();
-    s.detach_data_channels();
+    // Create a SettingEngine with Detach enabled
+    let s = setting_engine(&config.rtc)?;
 
     // Create the API object with the MediaEngine
     let api = APIBuilder::new()
@@ -55,47 +69,49 @@ pub async fn start_rtc_agent(
         .build();
 
     // Prepare the configuration
-    let rtc_config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: config.stun_server_urls.clone(),
-            ..Default::default()
-        }],
-        ..Default::default()
-    };
-
-    let (active_done_tx, mut active_done_rx) = tokio::sync::mpsc::channel::<()>(1);
+    let rtc_config = rtc_configuration(&config.rtc);
 
     // Create a new RTCPeerConnection
     let rtc_peer_conn = Arc::new(api.new_peer_connection(rtc_config).await?);
 
     // Set the handler for Peer connection state
     // This will notify you when the peer has connected/disconnected
+    let (state_tx, mut state_rx) = watch::channel(RTCPeerConnectionState::New);
     rtc_peer_conn.on_peer_connection_state_change(Box::new(
         move |state: RTCPeerConnectionState| {
             if state != RTCPeerConnectionState::Failed {
                 tracing::info!("rtc_peer_conn.on_peer_connection_state_change({state})");
             } else {
-                // Wait until PeerConnection has had no network activity for 30 seconds or another failure. It may be reconnected using an ICE Restart.
-                // Use webrtc.PeerConnectionStateDisconnected if you are interested in detecting faster timeout.
-                // Note that the PeerConnection may come back from PeerConnectionStateDisconnected.
+                // The client restarts ice through the signal server, keep the peer connection
+                // until it does or RECONNECT_TIMEOUT passes
                 tracing::error!(
                     "rtc_peer_conn.on_peer_connection_state_change(RTCPeerConnectionState::Failed)"
                 );
-                let _ = active_done_tx.try_send(());
             }
+            let _ = state_tx.send(state);
 
             Box::pin(async {})
         },
     ));
 
-    // Register data channel creation handling
+    // Register data channel creation handling, the client opens one per accepted connection
+    let (is_udp, port) = (client_sdp.is_udp, client_sdp.port);
+    let config_d = Arc::clone(&config);
+    let traffic = Arc::new(Traffic::default());
+    let traffic_d = Arc::clone(&traffic);
     rtc_peer_conn.on_data_channel(Box::new(move |rtc_data_ch: Arc<RTCDataChannel>| {
         let rtc_data_ch_id = rtc_data_ch.id();
         let rtc_data_ch_label = rtc_data_ch.label().to_owned();
         tracing::info!("on_data_channel(id: {rtc_data_ch_id}), label: {rtc_data_ch_label})");
+        let config = Arc::clone(&config_d);
+        let traffic = Arc::clone(&traffic_d);
 
         // Register channel opening handling
         Box::pin(async move {
+            if rtc_data_ch.protocol() == CONTROL_PROTOCOL {
+                return;
+            }
+
             let rtc_data_ch_clone = Arc::clone(&rtc_data_ch);
             let rtc_data_ch_clone_id = rtc_data_ch_id;
             let rtc_data_ch_clone_label = rtc_data_ch_label.clone();
@@ -114,30 +130,7 @@ pub async fn start_rtc_agent(
                 );
 
                 Box::pin(async move {
-                    let raw_data_ch = match rtc_data_ch_clone.detach().await {
-                        Ok(raw) => raw,
-                        Err(err) => {
-                            tracing::error!("rtc_data_ch_clone.detach() error, err: {err}");
-                            return;
-                        }
-                    };
-
-                    let socket = tokio::net::TcpStream::connect(SocketAddr::new(
-                        IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)),
-                        client_sdp.port,
-                    ))
-                    .await
-                    .unwrap();
-                    let (mut socket_r, mut socket_w) = tokio::io::split(socket);
-
-                    let raw_data_ch_r = Arc::clone(&raw_data_ch);
-                    tokio::spawn(async move {
-                        let _ = read_loop(raw_data_ch_r, &mut socket_w).await;
-                    });
-
-                    tokio::spawn(async move {
-                        let _ = write_loop(raw_data_ch, &mut socket_r).await;
-                    });
+                    proxy(rtc_data_ch_clone, is_udp, port, config, traffic).await;
                 })
             }));
 
@@ -149,8 +142,107 @@ pub async fn start_rtc_agent(
         })
     }));
 
+    // Publish local candidates as they are gathered instead of waiting for all of them
+    let session = client_sdp.session.clone();
+    let next_index = Arc::new(AtomicU32::new(0));
+    let restart = Arc::new(AtomicU32::new(client_sdp.restart));
+    let restart_c = Arc::clone(&restart);
+    let config_c = Arc::clone(&config);
+    rtc_peer_conn.on_ice_candidate(Box::new(move |candidate: Option<RTCIceCandidate>| {
+        let config = Arc::clone(&config_c);
+        let session = session.clone();
+        // indexes keep counting across ice restarts, each candidate has its own key
+        let index = next_index.fetch_add(1, Ordering::SeqCst);
+        let restart = restart_c.load(Ordering::SeqCst);
+        Box::pin(async move {
+            // clients without sessions do not trickle, they get every candidate in the answer
+            if session.is_empty() {
+                return;
+            }
+            if let Some(candidate) = to_candidate(session, index, restart, candidate) {
+                http::publish_agent_candidate(&config, &config.uuid, &candidate).await;
+            }
+        })
+    }));
+
+    answer(&rtc_peer_conn, &config, &client_sdp).await?;
+
+    // Add the candidates the client trickles after its offer
+    let mut remote_candidates = spawn_remote_candidates(&rtc_peer_conn, &config, &client_sdp);
+
+    // Close when the peer connection is not connected within RECONNECT_TIMEOUT, either after
+    // the first answer, after it got disconnected or after the last ice restart of the client
+    let mut deadline = Some(Instant::now() + RECONNECT_TIMEOUT);
+    let reason = loop {
+        let expired = async {
+            match deadline {
+                Some(deadline) => tokio::time::sleep_until(deadline).await,
+                None => std::future::pending().await,
+            }
+        };
+        tokio::select! {
+            changed = state_rx.changed() => {
+                if changed.is_err() {
+                    break "peer connection dropped";
+                }
+                let state = *state_rx.borrow_and_update();
+                match state {
+                    RTCPeerConnectionState::Connected => deadline = None,
+                    RTCPeerConnectionState::Disconnected | RTCPeerConnectionState::Failed => {
+                        deadline.get_or_insert_with(|| Instant::now() + RECONNECT_TIMEOUT);
+                    }
+                    RTCPeerConnectionState::Closed => break "peer connection closed",
+                    _ => {}
+                }
+            }
+            Some(offer) = offer_rx.recv() => {
+                // the client gives each restart its own timeout, so does the agent
+                if deadline.is_some() {
+                    deadline = Some(Instant::now() + RECONNECT_TIMEOUT);
+                }
+                // Candidates gathered from here on belong to the new ice generation
+                restart.store(offer.restart, Ordering::SeqCst);
+                if let Err(err) = answer(&rtc_peer_conn, &config, &offer).await {
+                    tracing::error!("session: {} ice restart error, err: {err}", offer.session);
+                    continue;
+                }
+                remote_candidates.abort();
+                remote_candidates = spawn_remote_candidates(&rtc_peer_conn, &config, &offer);
+            }
+            _ = expired => {
+                tracing::warn!(
+                    "session: {} not connected within {RECONNECT_TIMEOUT:?}",
+                    client_sdp.session
+                );
+                break "not connected";
+            }
+            _ = passive_done_rx.recv() => {
+                tracing::warn!("received passive done signal");
+                break "shutdown";
+            }
+        };
+    };
+
+    remote_candidates.abort();
+    rtc_peer_conn.close().await?;
+    tracing::info!(
+        "session: {} closed, reason: {reason}, sent: {} bytes, received: {} bytes",
+        client_sdp.session,
+        traffic.sent.load(Ordering::SeqCst),
+        traffic.received.load(Ordering::SeqCst)
+    );
+
+    Ok(())
+}
+
+// Apply an offer of the client and publish the answer under the same session and ice generation
+async fn answer(
+    rtc_peer_conn: &RTCPeerConnection,
+    config: &Configurations,
+    client_sdp: &Sdp,
+) -> Result<()> {
     // Set the remote SessionDescription
-    let client_session_desc = serde_json::from_str::<RTCSessionDescription>(&client_sdp.sdp)?;
+    let client_session_desc = serde_json::from_slice::<RTCSessionDescription>(&client_sdp.sdp)?;
     rtc_peer_conn
         .set_remote_description(client_session_desc)
         .await?;
@@ -158,73 +250,195 @@ pub async fn start_rtc_agent(
     // Create an answer
     let rtc_session_answer = rtc_peer_conn.create_answer(None).await?;
 
-    // Create channel that is blocked until ICE Gathering is complete
-    let mut gather_complete = rtc_peer_conn.gathering_complete_promise().await;
+    // Clients without sessions do not trickle, so the answer has to carry every candidate
+    let mut gather_complete = if client_sdp.session.is_empty() {
+        Some(rtc_peer_conn.gathering_complete_promise().await)
+    } else {
+        None
+    };
 
     // Sets the LocalDescription, and starts our UDP listeners
     rtc_peer_conn
         .set_local_description(rtc_session_answer)
         .await?;
 
-    // Block until ICE Gathering is complete, disabling trickle ICE
-    // we do this because we only can exchange one signaling message
-    // in a production application you should exchange ICE Candidates via OnICECandidate
-    let _ = gather_complete.recv().await;
+    if let Some(gather_complete) = gather_complete.as_mut() {
+        let _ = gather_complete.recv().await;
+    }
 
     if let Some(session_desc) = rtc_peer_conn.local_description().await {
-        let agent_session_desc = serde_json::to_string(&session_desc)?;
+        let agent_session_desc = serde_json::to_vec(&session_desc)?;
         let agent_sdp = Sdp {
             sdp: agent_session_desc,
-            ..client_sdp
+            ..client_sdp.clone()
         };
-        http::publish_agent_sdp(config, &config.uuid, &agent_sdp);
+        http::publish_agent_sdp(config, &config.uuid, &agent_sdp).await;
     } else {
         tracing::warn!("rtc_peer_conn.local_description error");
     }
 
-    tokio::select! {
-        _ = active_done_rx.recv() => {
-            tracing::warn!("received active done signal");
-        }
-        _ = passive_done_rx.recv() => {
-            tracing::warn!("received passive done signal");
+    Ok(())
+}
+
+// Add the candidates the client trickles for the session and ice generation of an offer
+fn spawn_remote_candidates(
+    rtc_peer_conn: &Arc<RTCPeerConnection>,
+    config: &Arc<Configurations>,
+    client_sdp: &Sdp,
+) -> JoinHandle<()> {
+    let config = Arc::clone(config);
+    let session = client_sdp.session.clone();
+    tokio::spawn(add_remote_candidates(
+        Arc::clone(rtc_peer_conn),
+        client_sdp.session.clone(),
+        client_sdp.restart,
+        move || {
+            let config = Arc::clone(&config);
+            let session = session.clone();
+            async move { http::query_client_candidates(&config, &config.uuid, &session).await }
+        },
+    ))
+}
+
+// Connect the local service of a data channel and pump both directions through it,
+// eof travels as fin either way and anything else resets both sides
+async fn proxy(
+    rtc_data_ch: Arc<RTCDataChannel>,
+    is_udp: bool,
+    port: u16,
+    config: Arc<Configurations>,
+    traffic: Arc<Traffic>,
+) {
+    let label = rtc_data_ch.label().to_owned();
+    let proxy_socket = match socket::connect(is_udp, port, &config.unix_sockets).await {
+        Ok(proxy_socket) => proxy_socket,
+        Err(err) => {
+            tracing::error!(
+                "connect to {} port {port} error, err: {err}",
+                if is_udp { "udp" } else { "tcp" }
+            );
+            let _ = rtc_data_ch.close().await;
+            return;
         }
     };
 
-    rtc_peer_conn.close().await?;
+    let raw_data_ch = match rtc_data_ch.detach().await {
+        Ok(raw) => raw,
+        Err(err) => {
+            tracing::error!("rtc_data_ch.detach() error, err: {err}");
+            return;
+        }
+    };
 
-    Ok(())
+    tokio::spawn(async move {
+        let idle = Idle::default();
+        let pumps = async {
+            tokio::try_join!(
+                write_loop(&raw_data_ch, proxy_socket.as_ref(), &config, &idle),
+                read_loop(&raw_data_ch, proxy_socket.as_ref(), &idle),
+            )
+        };
+        let pumped = if proxy_socket.is_datagram() {
+            tokio::select! {
+                pumped = pumps => pumped,
+                _ = idle.expired(UDP_IDLE_TIMEOUT) => Err(CloseReason::Idle),
+            }
+        } else {
+            pumps.await
+        };
+        let reason = match pumped {
+            Ok(_) => CloseReason::Finished,
+            Err(reason) => {
+                if let Err(err) = proxy_socket.reset() {
+                    tracing::warn!("proxy_socket.reset error, err: {err}");
+                }
+                reason
+            }
+        };
+        let _ = raw_data_ch.close().await;
+
+        traffic.add(&raw_data_ch);
+        tracing::info!(
+            "data channel {label} closed, reason: {reason}, sent: {} bytes, received: {} bytes",
+            raw_data_ch.bytes_sent(),
+            raw_data_ch.bytes_received()
+        );
+    });
 }
 
+// Forward data channel messages to the local socket until the peer sent fin
 async fn read_loop(
-    data_ch_r: Arc<webrtc::data::data_channel::DataChannel>,
-    socket_w: &mut tokio::io::WriteHalf<tokio::net::TcpStream>,
-) -> Result<()> {
-    let mut buffer = vec![0u8; MESSAGE_SIZE];
+    data_ch_r: &webrtc::data::data_channel::DataChannel,
+    proxy_socket: &dyn ProxySocket,
+    idle: &Idle,
+) -> Result<(), CloseReason> {
+    // one buffer for the whole channel, a message must fit in it at once
+    let mut buffer = BytesMut::zeroed(MAX_MESSAGE_SIZE);
     loop {
-        let n = match data_ch_r.read(&mut buffer).await {
-            Ok(n) => {
-                socket_w.write(&buffer[..n]).await?;
-                n
-            }
-            Err(err) => {
-                tracing::error!("data_ch.read error, err: {err}");
+        let n = match data_ch_r.read_data_channel(&mut buffer).await {
+            // an empty string message is the fin of the peer, data is always binary
+            Ok((0, true)) if !proxy_socket.is_datagram() => {
+                proxy_socket.teardown().await?;
                 return Ok(());
             }
+            Ok((0, _)) => return Err(CloseReason::ChannelClosed),
+            Ok((n, _)) => n,
+            // a short buffer error means the peer sends messages larger than MAX_MESSAGE_SIZE
+            Err(err) => return Err(err.into()),
         };
+        idle.touch();
+        if let Err(err) = proxy_socket.send(&buffer[..n]).await {
+            if !proxy_socket.is_datagram() {
+                return Err(err.into());
+            }
+            tracing::warn!("proxy_socket.send error, err: {err}");
+        }
     }
 }
 
+// Forward what the local socket reads to the data channel, exactly the bytes read in
+// messages of at most the message size, and send fin on eof
 async fn write_loop(
-    data_ch_w: Arc<webrtc::data::data_channel::DataChannel>,
-    socket_r: &mut tokio::io::ReadHalf<tokio::net::TcpStream>,
-) -> Result<()> {
-    let mut result = Result::<usize>::Ok(0);
-    while result.is_ok() {
-        let mut buf = vec![0u8; MESSAGE_SIZE];
-        socket_r.read(&mut buf).await?;
-        result = data_ch_w.write(&Bytes::from(buf)).await.map_err(Into::into);
+    data_ch_w: &Arc<webrtc::data::data_channel::DataChannel>,
+    proxy_socket: &dyn ProxySocket,
+    config: &Configurations,
+    idle: &Idle,
+) -> Result<(), CloseReason> {
+    let backpressure = Backpressure::new(Arc::clone(data_ch_w), &config.rtc);
+    let size = message_size(proxy_socket, config);
+    let mut buffer = BytesMut::with_capacity(size);
+    loop {
+        backpressure.wait().await;
+        // takes the allocation back once the messages split off it were sent
+        buffer.reserve(size);
+        let n = match proxy_socket.read(&mut buffer).await {
+            Ok(0) if !proxy_socket.is_datagram() => {
+                data_ch_w.write_data_channel(&Bytes::new(), true).await?;
+                return Ok(());
+            }
+            // empty messages are not tunneled, the peer reads them as a closed channel
+            Ok(0) => continue,
+            Ok(n) => n,
+            // e.g. connection refused by the icmp of a previous udp send, keep going
+            Err(err) if proxy_socket.is_datagram() => {
+                tracing::warn!("proxy_socket.read error, err: {err}");
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        idle.touch();
+        let mut data = buffer.split_to(n).freeze();
+        while !data.is_empty() {
+            let message = data.split_to(data.len().min(size));
+            data_ch_w.write(&message).await?;
+        }
     }
+}
 
-    Ok(())
+fn message_size(proxy_socket: &dyn ProxySocket, config: &Configurations) -> usize {
+    if proxy_socket.is_datagram() {
+        UDP_MESSAGE_SIZE
+    } else {
+        config.rtc.max_message_size
+    }
 }
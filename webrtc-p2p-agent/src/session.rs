@@ -0,0 +1,254 @@
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+
+use signal_protocol::Sdp;
+use tokio::sync::mpsc;
+use tokio::task::JoinHandle;
+
+use crate::data::Configurations;
+use crate::rtc;
+
+// One peer connection serving one client session
+struct Session {
+    done_tx: mpsc::Sender<()>,
+    // ice restart offers of the client, applied to the running peer connection
+    offer_tx: mpsc::Sender<Sdp>,
+    // ice generation of the last offer handed over
+    restart: u32,
+    handle: JoinHandle<()>,
+}
+
+// Peer connections by client session id, each torn down on its own
+#[derive(Default)]
+pub struct SessionTable {
+    running: HashMap<String, Session>,
+    // sessions already answered whose client sdp is still listed by the signal server
+    finished: HashSet<String>,
+}
+
+impl SessionTable {
+    // Start a peer connection for every client sdp not seen before and hand ice restarts
+    // of running sessions over to their peer connections
+    pub fn update(&mut self, config: &Arc<Configurations>, client_sdps: Vec<Sdp>) {
+        self.update_with(client_sdps, |client_sdp, mut offer_rx, mut done_rx| {
+            let config = Arc::clone(config);
+            let session = client_sdp.session.clone();
+            tokio::spawn(async move {
+                match rtc::start_rtc_agent(config, client_sdp, &mut offer_rx, &mut done_rx).await {
+                    Ok(()) => tracing::info!("session: {session} closed"),
+                    Err(e) => tracing::error!("session: {session} failed, e: {:?}", e),
+                }
+            })
+        });
+    }
+
+    // Same as update with the task serving a new session left to start
+    fn update_with(
+        &mut self,
+        client_sdps: Vec<Sdp>,
+        mut start: impl FnMut(Sdp, mpsc::Receiver<Sdp>, mpsc::Receiver<()>) -> JoinHandle<()>,
+    ) {
+        self.reap();
+
+        let listed = client_sdps
+            .iter()
+            .map(|sdp| sdp.session.clone())
+            .collect::<HashSet<_>>();
+        self.finished.retain(|session| listed.contains(session));
+
+        for client_sdp in client_sdps {
+            if client_sdp.sdp.is_empty() || self.finished.contains(&client_sdp.session) {
+                continue;
+            }
+            if let Some(session) = self.running.get_mut(&client_sdp.session) {
+                if client_sdp.restart > session.restart {
+                    tracing::info!(
+                        "session: {} ice restart {}",
+                        client_sdp.session,
+                        client_sdp.restart
+                    );
+                    session.restart = client_sdp.restart;
+                    let _ = session.offer_tx.try_send(client_sdp);
+                }
+                continue;
+            }
+            self.spawn(client_sdp, &mut start);
+        }
+    }
+
+    pub fn len(&self) -> usize {
+        self.running.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.running.is_empty()
+    }
+
+    // Signal every peer connection to close and wait for them
+    pub async fn shutdown(&mut self) {
+        for (_, session) in self.running.drain() {
+            let _ = session.done_tx.try_send(());
+            let _ = session.handle.await;
+        }
+    }
+
+    fn spawn(
+        &mut self,
+        client_sdp: Sdp,
+        start: impl FnOnce(Sdp, mpsc::Receiver<Sdp>, mpsc::Receiver<()>) -> JoinHandle<()>,
+    ) {
+        let session = client_sdp.session.clone();
+        tracing::info!(
+            "session: {session} started, is_udp: {}, port: {}",
+            client_sdp.is_udp,
+            client_sdp.port
+        );
+
+        let restart = client_sdp.restart;
+        let (done_tx, done_rx) = mpsc::channel(1);
+        let (offer_tx, offer_rx) = mpsc::channel(1);
+        let handle = start(client_sdp, offer_rx, done_rx);
+        self.running.insert(
+            session,
+            Session {
+                done_tx,
+                offer_tx,
+                restart,
+                handle,
+            },
+        );
+    }
+
+    // Forget peer connections that closed by themselves
+    fn reap(&mut self) {
+        let closed = self
+            .running
+            .iter()
+            .filter(|(_, session)| session.handle.is_finished())
+            .map(|(session, _)| session.clone())
+            .collect::<Vec<_>>();
+        for session in closed {
+            self.running.remove(&session);
+            self.finished.insert(session);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn sdp(session: &str, restart: u32) -> Sdp {
+        Sdp {
+            sdp: vec![1],
+            session: session.to_string(),
+            restart,
+            ..Default::default()
+        }
+    }
+
+    // Sessions that report the offers they are handed and run until told to close
+    fn serve(
+        events: mpsc::UnboundedSender<String>,
+    ) -> impl FnMut(Sdp, mpsc::Receiver<Sdp>, mpsc::Receiver<()>) -> JoinHandle<()> {
+        move |client_sdp, mut offer_rx, mut done_rx| {
+            let events = events.clone();
+            let _ = events.send(format!("start {}", client_sdp.session));
+            tokio::spawn(async move {
+                loop {
+                    tokio::select! {
+                        Some(offer) = offer_rx.recv() => {
+                            let _ = events.send(format!("restart {} {}", offer.session, offer.restart));
+                        }
+                        _ = done_rx.recv() => {
+                            let _ = events.send(format!("close {}", client_sdp.session));
+                            break;
+                        }
+                    }
+                }
+            })
+        }
+    }
+
+    fn drain(events_rx: &mut mpsc::UnboundedReceiver<String>) -> Vec<String> {
+        let mut events = vec![];
+        while let Ok(event) = events_rx.try_recv() {
+            events.push(event);
+        }
+        events
+    }
+
+    async fn settle() {
+        for _ in 0..10 {
+            tokio::task::yield_now().await;
+        }
+    }
+
+    #[tokio::test]
+    async fn update_starts_each_session_once() {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let mut table = SessionTable::default();
+        let mut empty = sdp("s3", 0);
+        empty.sdp.clear();
+
+        table.update_with(
+            vec![sdp("s1", 0), sdp("s2", 0), empty],
+            serve(events_tx.clone()),
+        );
+        table.update_with(vec![sdp("s1", 0), sdp("s2", 0)], serve(events_tx));
+
+        let mut events = drain(&mut events_rx);
+        events.sort();
+        assert_eq!(events, vec!["start s1", "start s2"]);
+        assert_eq!(table.len(), 2);
+    }
+
+    #[tokio::test]
+    async fn update_hands_newer_restarts_over() {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let mut table = SessionTable::default();
+
+        table.update_with(vec![sdp("s1", 0)], serve(events_tx.clone()));
+        table.update_with(vec![sdp("s1", 1)], serve(events_tx.clone()));
+        settle().await;
+        // a client sdp listed again after the restart is not handed over twice
+        table.update_with(vec![sdp("s1", 1)], serve(events_tx));
+        settle().await;
+
+        assert_eq!(drain(&mut events_rx), vec!["start s1", "restart s1 1"]);
+    }
+
+    #[tokio::test]
+    async fn closed_sessions_are_not_restarted_while_listed() {
+        let mut starts = 0;
+        let mut table = SessionTable::default();
+        let mut start = |_: Sdp, _: mpsc::Receiver<Sdp>, _: mpsc::Receiver<()>| {
+            starts += 1;
+            tokio::spawn(async {})
+        };
+
+        table.update_with(vec![sdp("s1", 0)], &mut start);
+        settle().await;
+        table.update_with(vec![sdp("s1", 0)], &mut start);
+        assert!(table.is_empty());
+
+        // once the signal server forgot the sdp, a new one of that session starts again
+        table.update_with(vec![], &mut start);
+        table.update_with(vec![sdp("s1", 0)], &mut start);
+        assert_eq!(starts, 2);
+    }
+
+    #[tokio::test]
+    async fn shutdown_closes_every_session() {
+        let (events_tx, mut events_rx) = mpsc::unbounded_channel();
+        let mut table = SessionTable::default();
+        table.update_with(vec![sdp("s1", 0), sdp("s2", 0)], serve(events_tx));
+
+        table.shutdown().await;
+
+        let mut events = drain(&mut events_rx);
+        events.sort();
+        assert_eq!(events, vec!["close s1", "close s2", "start s1", "start s2"]);
+        assert!(table.is_empty());
+    }
+}
@@ -1,92 +1,194 @@
-use std::future::Future;
-use std::net::{Ipv4Addr, SocketAddr};
-use std::{io, net::IpAddr};
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Shutdown, SocketAddr};
+use std::sync::Arc;
+use std::time::Duration;
+
+use async_trait::async_trait;
+use bytes::BytesMut;
 use tokio::net::{TcpStream, UdpSocket};
 
-pub trait ProxySocket: Send {
-    fn teardown<'a>(&'a mut self) -> Box<dyn Future<Output = io::Result<()>> + Send + 'a>;
-    fn send<'a>(
-        &'a mut self,
-        data: &'a [u8],
-    ) -> Box<dyn Future<Output = io::Result<usize>> + Send + 'a>;
-    fn read<'a>(
-        &'a mut self,
-        data: &'a mut [u8],
-    ) -> Box<dyn Future<Output = io::Result<(usize, SocketAddr)>> + Send + 'a>;
+// A connected local socket a data channel is pumped into, shared by both pump directions
+#[async_trait]
+pub trait ProxySocket: Send + Sync {
+    // datagram sockets keep message boundaries and may read empty messages
+    fn is_datagram(&self) -> bool;
+
+    // stop sending, the peer reads eof on stream sockets
+    async fn teardown(&self) -> io::Result<()>;
+
+    // have the connection reset instead of closed gracefully once the socket is dropped
+    fn reset(&self) -> io::Result<()>;
+
+    // send all of data, one datagram on datagram sockets
+    async fn send(&self, data: &[u8]) -> io::Result<usize>;
+
+    // read what is available into the spare capacity of data, 0 means eof on stream sockets
+    async fn read(&self, data: &mut BytesMut) -> io::Result<usize>;
 }
 
 pub struct ProxyTcpSocket {
-    addr: SocketAddr,
     tcp_stream: TcpStream,
 }
 
 pub struct ProxyUdpSocket {
-    addr: SocketAddr,
     udp_socket: UdpSocket,
 }
 
-pub struct X {
-    proxy_socket: Box<dyn ProxySocket>,
+#[cfg(unix)]
+pub struct ProxyUnixSocket {
+    unix_stream: tokio::net::UnixStream,
 }
 
-impl X {
-    pub fn new(proxy_socket: Box<dyn ProxySocket>) -> Self {
-        Self { proxy_socket }
+// Connect to the local service a client asked for, tcp ports listed in unix_sockets
+// are served by the unix domain socket at that path instead
+pub async fn connect(
+    is_udp: bool,
+    port: u16,
+    unix_sockets: &std::collections::BTreeMap<u16, String>,
+) -> io::Result<Arc<dyn ProxySocket>> {
+    if is_udp {
+        return Ok(Arc::new(ProxyUdpSocket::setup(port).await?));
+    }
+
+    #[cfg(unix)]
+    if let Some(path) = unix_sockets.get(&port) {
+        return Ok(Arc::new(ProxyUnixSocket::setup(path).await?));
+    }
+    #[cfg(not(unix))]
+    if unix_sockets.contains_key(&port) {
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "unix domain sockets are not supported on this platform",
+        ));
     }
+
+    Ok(Arc::new(ProxyTcpSocket::setup(port).await?))
 }
 
 impl ProxyTcpSocket {
     pub async fn setup(port: u16) -> io::Result<ProxyTcpSocket> {
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
         let tcp_stream = TcpStream::connect(addr).await?;
-        Ok(Self { addr, tcp_stream })
+        Ok(Self { tcp_stream })
     }
 }
 
+#[async_trait]
 impl ProxySocket for ProxyTcpSocket {
-    fn teardown<'a>(&'a mut self) -> Box<dyn Future<Output = io::Result<()>> + Send + 'a> {
-        Box::new(async move { Ok(()) })
+    fn is_datagram(&self) -> bool {
+        false
+    }
+
+    async fn teardown(&self) -> io::Result<()> {
+        socket2::SockRef::from(&self.tcp_stream).shutdown(Shutdown::Write)
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        socket2::SockRef::from(&self.tcp_stream).set_linger(Some(Duration::ZERO))
     }
 
-    fn send<'a>(
-        &'a mut self,
-        data: &'a [u8],
-    ) -> Box<dyn Future<Output = io::Result<usize>> + Send + 'a> {
-        Box::new(async move { self.tcp_stream.try_write(data) })
+    async fn send(&self, data: &[u8]) -> io::Result<usize> {
+        let mut sent = 0;
+        while sent < data.len() {
+            self.tcp_stream.writable().await?;
+            match self.tcp_stream.try_write(&data[sent..]) {
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sent)
     }
 
-    fn read<'a>(
-        &'a mut self,
-        data: &'a mut [u8],
-    ) -> Box<dyn Future<Output = io::Result<(usize, SocketAddr)>> + Send + 'a> {
-        Box::new(async move { Ok((self.tcp_stream.try_read(data)?, self.addr)) })
+    async fn read(&self, data: &mut BytesMut) -> io::Result<usize> {
+        loop {
+            self.tcp_stream.readable().await?;
+            match self.tcp_stream.try_read_buf(data) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
     }
 }
 
 impl ProxyUdpSocket {
     pub async fn setup(port: u16) -> io::Result<ProxyUdpSocket> {
-        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::new(127, 0, 0, 1)), port);
-        let udp_socket = UdpSocket::bind(addr).await?;
-        Ok(Self { addr, udp_socket })
+        let addr = SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), port);
+        // an ephemeral local port per data channel keeps replies of different sources apart
+        let udp_socket =
+            UdpSocket::bind(SocketAddr::new(IpAddr::V4(Ipv4Addr::LOCALHOST), 0)).await?;
+        udp_socket.connect(addr).await?;
+        Ok(Self { udp_socket })
     }
 }
 
+#[async_trait]
 impl ProxySocket for ProxyUdpSocket {
-    fn teardown<'a>(&'a mut self) -> Box<dyn Future<Output = io::Result<()>> + Send + 'a> {
-        Box::new(async move { Ok(()) })
+    fn is_datagram(&self) -> bool {
+        true
+    }
+
+    async fn teardown(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    fn reset(&self) -> io::Result<()> {
+        Ok(())
+    }
+
+    async fn send(&self, data: &[u8]) -> io::Result<usize> {
+        self.udp_socket.send(data).await
+    }
+
+    async fn read(&self, data: &mut BytesMut) -> io::Result<usize> {
+        self.udp_socket.recv_buf(data).await
+    }
+}
+
+#[cfg(unix)]
+impl ProxyUnixSocket {
+    pub async fn setup(path: &str) -> io::Result<ProxyUnixSocket> {
+        let unix_stream = tokio::net::UnixStream::connect(path).await?;
+        Ok(Self { unix_stream })
+    }
+}
+
+#[cfg(unix)]
+#[async_trait]
+impl ProxySocket for ProxyUnixSocket {
+    fn is_datagram(&self) -> bool {
+        false
+    }
+
+    async fn teardown(&self) -> io::Result<()> {
+        socket2::SockRef::from(&self.unix_stream).shutdown(Shutdown::Write)
+    }
+
+    // unix domain sockets have no reset, the peer reads eof
+    fn reset(&self) -> io::Result<()> {
+        Ok(())
     }
 
-    fn send<'a>(
-        &'a mut self,
-        data: &'a [u8],
-    ) -> Box<dyn Future<Output = io::Result<usize>> + Send + 'a> {
-        Box::new(async move { self.udp_socket.send_to(data, self.addr).await })
+    async fn send(&self, data: &[u8]) -> io::Result<usize> {
+        let mut sent = 0;
+        while sent < data.len() {
+            self.unix_stream.writable().await?;
+            match self.unix_stream.try_write(&data[sent..]) {
+                Ok(n) => sent += n,
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                Err(e) => return Err(e),
+            }
+        }
+        Ok(sent)
     }
 
-    fn read<'a>(
-        &'a mut self,
-        data: &'a mut [u8],
-    ) -> Box<dyn Future<Output = io::Result<(usize, SocketAddr)>> + Send + 'a> {
-        Box::new(async move { self.udp_socket.recv_from(data).await })
+    async fn read(&self, data: &mut BytesMut) -> io::Result<usize> {
+        loop {
+            self.unix_stream.readable().await?;
+            match self.unix_stream.try_read_buf(data) {
+                Err(e) if e.kind() == io::ErrorKind::WouldBlock => continue,
+                result => return result,
+            }
+        }
     }
 }
@@ -1,7 +1,9 @@
 use config_file_derives::ConfigFile;
 use config_file_types;
 use serde::{Deserialize, Serialize};
+use signal_protocol::routes;
 use tracing;
+use webrtc_p2p_common::RtcConfigurations;
 
 static CONFIG_PATH: &str = "client.json";
 
@@ -11,35 +13,77 @@ pub struct Configurations {
     #[serde(skip)]
     path: String,
 
-    pub stun_server_urls: Vec<String>,
+    // ice servers, candidate gathering and tunnel options
+    #[serde(flatten)]
+    pub rtc: RtcConfigurations,
 
     pub signal_server_url: String,
-    pub query_agent_url: String,
-    pub publish_client_sdp_url: String,
-    pub query_agent_sdp_url: String,
+    // bearer token sent to the signal server, empty when it runs without auth
+    #[serde(default)]
+    pub token: String,
+    #[serde(default)]
+    pub agents_url: String,
+    #[serde(default)]
+    pub client_sdps_url: String,
+    #[serde(default)]
+    pub agent_sdps_url: String,
+    #[serde(default)]
+    pub client_candidates_url: String,
+    #[serde(default)]
+    pub agent_candidates_url: String,
+    // v1 urls superseded by the v2 urls above, a path prefix in front of them moves there
+    #[serde(default, skip_serializing)]
+    query_agent_url: String,
+    #[serde(default, skip_serializing)]
+    publish_client_sdp_url: String,
+    #[serde(default, skip_serializing)]
+    query_agent_sdp_url: String,
 }
 
 impl Configurations {
     pub fn load_file() -> Self {
         let mut config = Self::load(CONFIG_PATH, true).unwrap();
         let mut update = false;
-        if config.stun_server_urls.is_empty() {
-            config.stun_server_urls = vec!["stun:stun.l.google.com:19302".to_owned()];
+        if config.rtc.load_defaults() {
             update = true;
         }
         if config.signal_server_url.is_empty() {
             tracing::error!("config.signal_server_url.is_empty()");
         }
-        if config.query_agent_url.is_empty() {
-            config.query_agent_url = String::from("/query/agent");
+        let v1_urls = [
+            &config.query_agent_url,
+            &config.publish_client_sdp_url,
+            &config.query_agent_sdp_url,
+        ];
+        let prefix = v1_urls
+            .iter()
+            .find_map(|url| routes::v1_url_prefix(url))
+            .unwrap_or_default()
+            .to_string();
+        if let Some(url) = v1_urls
+            .iter()
+            .find(|url| !url.is_empty() && routes::v1_url_prefix(url).is_none())
+        {
+            tracing::warn!("v1 url {} can not be migrated to a v2 url", url);
+        }
+        if config.agents_url.is_empty() {
+            config.agents_url = format!("{}{}", prefix, routes::AGENTS_URL);
+            update = true;
+        }
+        if config.client_sdps_url.is_empty() {
+            config.client_sdps_url = format!("{}{}", prefix, routes::CLIENT_SDPS_URL);
+            update = true;
+        }
+        if config.agent_sdps_url.is_empty() {
+            config.agent_sdps_url = format!("{}{}", prefix, routes::AGENT_SDPS_URL);
             update = true;
         }
-        if config.publish_client_sdp_url.is_empty() {
-            config.publish_client_sdp_url = String::from("/publish/client/sdp");
+        if config.client_candidates_url.is_empty() {
+            config.client_candidates_url = format!("{}{}", prefix, routes::CLIENT_CANDIDATES_URL);
             update = true;
         }
-        if config.query_agent_sdp_url.is_empty() {
-            config.query_agent_sdp_url = String::from("/query/agent/sdp");
+        if config.agent_candidates_url.is_empty() {
+            config.agent_candidates_url = format!("{}{}", prefix, routes::AGENT_CANDIDATES_URL);
             update = true;
         }
         if update {
@@ -48,17 +92,3 @@ impl Configurations {
         config
     }
 }
-
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
-pub struct Agent {
-    pub uuid: String,
-    pub name: String,
-    pub os: String,
-}
-
-#[derive(Clone, Debug, Default, Deserialize, Serialize, PartialEq)]
-pub struct Sdp {
-    pub sdp: String,
-    pub is_udp: bool,
-    pub port: u16,
-}
@@ -1,7 +1,8 @@
+use std::sync::Arc;
+
 use anyhow::Result;
 use time::{macros::format_description, UtcOffset};
 
-use tracing;
 use tracing_subscriber::{self, fmt::time::OffsetTime};
 
 pub mod cli;
@@ -31,10 +32,11 @@ async fn main() -> Result<()> {
     if config.signal_server_url.is_empty() {
         return Err(anyhow::anyhow!("invalid config, missing signal_server_url"));
     }
+    config.rtc.check()?;
 
     match cli.command {
         Commands::Query { name } => {
-            let agents = http::query_agent(&config, &name);
+            let agents = http::query_agent(&config, &name).await;
             println!("agents: {:?}", agents);
         }
         Commands::Connect {
@@ -44,21 +46,27 @@ async fn main() -> Result<()> {
             local_port,
             remote_port,
         } => {
-            let agents = http::query_agent(&config, &name);
+            let agents = http::query_agent(&config, &name).await;
             if agents.is_empty() {
                 tracing::error!("no agents found for name: {}", name);
             } else {
-                let agent = if agents.len() == 1 {
-                    agents[0].clone()
-                } else {
-                    agents
-                        .iter()
-                        .filter(|agent| agent.uuid == uuid)
-                        .next()
-                        .unwrap()
-                        .clone()
+                // several agents may share a name, --uuid picks one of them
+                let agent = match agents.iter().find(|agent| agent.uuid == uuid) {
+                    Some(agent) => agent.clone(),
+                    None if uuid.is_empty() && agents.len() == 1 => agents[0].clone(),
+                    None => {
+                        let uuids = agents
+                            .iter()
+                            .map(|agent| agent.uuid.as_str())
+                            .collect::<Vec<_>>()
+                            .join(", ");
+                        return Err(anyhow::anyhow!(
+                            "no agent {name} with uuid {uuid:?}, pass one of --uuid {uuids}"
+                        ));
+                    }
                 };
-                rtc::start_rtc_client(&config, agent, udp, local_port, remote_port).await?;
+                rtc::start_rtc_client(Arc::new(config), agent, udp, local_port, remote_port)
+                    .await?;
             }
         }
     }
@@ -1,29 +1,76 @@
+use std::collections::HashMap;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicU32, Ordering};
 use std::{sync::Arc, time::Duration};
 
 use anyhow::Result;
-use bytes::Bytes;
+use bytes::{Bytes, BytesMut};
 use tokio::io::{AsyncReadExt, AsyncWriteExt};
+use tokio::sync::mpsc::error::TrySendError;
+use tokio::sync::watch;
+use tokio::task::JoinHandle;
 use tracing;
+use webrtc::api::interceptor_registry::register_default_interceptors;
 use webrtc::api::media_engine::MediaEngine;
-use webrtc::api::APIBuilder;
-use webrtc::api::{
-    interceptor_registry::register_default_interceptors, setting_engine::SettingEngine,
-};
+use webrtc::api::{APIBuilder, API};
+use webrtc::data_channel::data_channel_init::RTCDataChannelInit;
 use webrtc::data_channel::data_channel_message::DataChannelMessage;
-use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::ice_transport::ice_candidate::RTCIceCandidate;
 use webrtc::interceptor::registry::Registry;
-use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::offer_answer_options::RTCOfferOptions;
+use webrtc::peer_connection::peer_connection_state::RTCPeerConnectionState;
 use webrtc::peer_connection::sdp::session_description::RTCSessionDescription;
+use webrtc::peer_connection::RTCPeerConnection;
 
-use crate::{
-    data::{Agent, Configurations, Sdp},
-    http,
+use signal_protocol::{Agent, Sdp};
+use webrtc_p2p_common::rtc::{
+    add_remote_candidates, rtc_configuration, setting_engine, to_candidate,
+};
+use webrtc_p2p_common::tunnel::{
+    Backpressure, CloseReason, Idle, Traffic, MAX_MESSAGE_SIZE, UDP_IDLE_TIMEOUT, UDP_MESSAGE_SIZE,
 };
 
-const MESSAGE_SIZE: usize = 1500;
+use crate::{data::Configurations, http};
+
+// data channel protocols, the agent dials the target for every channel but the control one
+const CONTROL_PROTOCOL: &str = "control";
+const TCP_PROTOCOL: &str = "tcp";
+const UDP_PROTOCOL: &str = "udp";
+// datagrams queued per source while its data channel opens or sends
+const UDP_QUEUE_SIZE: usize = 256;
+// ice restarts tried on a failed peer connection before a new session replaces it
+const MAX_ICE_RESTARTS: u32 = 3;
+// time one ice restart has to bring the peer connection back
+const ICE_RESTART_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Why a peer connection stopped serving local connections
+enum Stopped {
+    Interrupted,
+    Failed,
+}
+
+// The local endpoint, bound once and served by every peer connection of the tunnel
+enum LocalSocket {
+    Tcp(tokio::net::TcpListener),
+    Udp(Arc<tokio::net::UdpSocket>),
+}
+
+// A peer connection to the agent and the signalling state of its session
+struct Peer {
+    rtc_peer_connection: Arc<RTCPeerConnection>,
+    config: Arc<Configurations>,
+    agent_uuid: String,
+    // the last offer published, its restart is the ice generation of the session
+    offer: Sdp,
+    // ice generation the gathered candidates are published for
+    restart: Arc<AtomicU32>,
+    state_rx: watch::Receiver<RTCPeerConnectionState>,
+    remote_candidates: Option<JoinHandle<()>>,
+    traffic: Arc<Traffic>,
+}
 
 pub async fn start_rtc_client(
-    config: &Configurations,
+    config: Arc<Configurations>,
     agent: Agent,
     is_udp: bool,
     local_port: u16,
@@ -44,9 +91,8 @@ pub async fn start_rtc_client(
     // Use the default set of Interceptors
     registry = register_default_interceptors(registry, &mut media_engine)?;
 
-    // Create a SettingEngine and enable Detach
-    let mut s = SettingEngine::default();
-    s.detach_data_channels();
+    // Create a SettingEngine with Detach enabled
+    let s = setting_engine(&config.rtc)?;
 
     // Create the API object with the MediaEngine
     let api = APIBuilder::new()
@@ -55,21 +101,7 @@ pub async fn start_rtc_client(
         .with_setting_engine(s)
         .build();
 
-    // Prepare the configuration
-    let rtc_config = RTCConfiguration {
-        ice_servers: vec![RTCIceServer {
-            urls: config.stun_server_urls.clone(),
-            ..Default::default()
-        }],
-        ..Default::default()
-    };
-
-    // Create a new RTCPeerConnection
-    let rtc_peer_connection = Arc::new(api.new_peer_connection(rtc_config).await?);
-
-    let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<()>(1);
-
-    // Create the data channel
+    // Label of the control data channel, tunnel data channels are suffixed with their source
     let label = format!(
         "{}-{}-{}-{}-{}",
         agent.name,
@@ -78,173 +110,593 @@ pub async fn start_rtc_client(
         if is_udp { "udp" } else { "tcp" },
         remote_port
     );
-    let label_1 = label.clone();
-    let label_2 = label_1.clone();
-    let rtc_data_channel = rtc_peer_connection
-        .create_data_channel(&label, None)
-        .await?;
 
-    let _rtc_data_channel_clone = Arc::clone(&rtc_data_channel);
-    rtc_data_channel.on_open(Box::new(move || {
-        tracing::info!("rtc_data_channel.on_open(label: {label_1})");
-        Box::pin(async {})
-    }));
+    // Bind the local port once, applications keep using it while the tunnel reconnects
+    let local_socket = if is_udp {
+        LocalSocket::Udp(Arc::new(
+            tokio::net::UdpSocket::bind(format!("127.0.0.1:{}", local_port)).await?,
+        ))
+    } else {
+        LocalSocket::Tcp(tokio::net::TcpListener::bind(format!("127.0.0.1:{}", local_port)).await?)
+    };
 
-    rtc_data_channel.on_close(Box::new(move || {
-        tracing::info!("rtc_data_channel.on_close(label: {label_2})");
-        Box::pin(async {})
-    }));
+    let (done_tx, mut done_rx) = tokio::sync::mpsc::channel::<()>(1);
+    tokio::spawn(async move {
+        if tokio::signal::ctrl_c().await.is_ok() {
+            tracing::info!("received ctrl-c, closing the tunnel");
+            let _ = done_tx.send(()).await;
+        }
+    });
 
-    rtc_data_channel.on_message(Box::new(move |msg: DataChannelMessage| {
-        // forward
-        Box::pin(async {})
-    }));
+    loop {
+        let offer = Sdp {
+            is_udp,
+            port: remote_port,
+            session: uuid::Uuid::new_v4().to_string(),
+            ..Default::default()
+        };
+        let mut peer = tokio::select! {
+            peer = Peer::connect(&api, &config, &agent.uuid, &label, offer) => peer?,
+            _ = done_rx.recv() => return Ok(()),
+        };
+        // data channels of udp sources, they survive ice restarts like the tcp ones and
+        // close with the peer connection once a new session replaces it
+        let mut sources = HashMap::new();
+
+        loop {
+            let stopped = match &local_socket {
+                LocalSocket::Tcp(tcp_server) => {
+                    serve_tcp(&peer, tcp_server, &label, &mut done_rx).await?
+                }
+                LocalSocket::Udp(udp_socket) => {
+                    serve_udp(&peer, udp_socket, &label, &mut sources, &mut done_rx).await?
+                }
+            };
+            let restarted = match stopped {
+                Stopped::Interrupted => None,
+                Stopped::Failed => tokio::select! {
+                    restarted = peer.recover() => Some(restarted),
+                    _ = done_rx.recv() => None,
+                },
+            };
+            match restarted {
+                // the same peer connection serves again, open data channels survived
+                Some(true) => continue,
+                Some(false) => {
+                    tracing::warn!(
+                        "session: {} not recovered, reconnecting with a new session",
+                        peer.offer.session
+                    );
+                    peer.close("not recovered").await?;
+                    break;
+                }
+                None => {
+                    peer.close("interrupted").await?;
+                    return Ok(());
+                }
+            }
+        }
+    }
+}
 
-    // Set the handler for ICE connection state
-    rtc_peer_connection.on_ice_connection_state_change(Box::new(
-        |connection_state: webrtc::ice_transport::ice_connection_state::RTCIceConnectionState| {
-            tracing::info!(
-                "rtc_peer_connection.on_ice_connection_state_change(state: {connection_state})"
-            );
+impl Peer {
+    // Create a peer connection with its control data channel and negotiate a new session
+    async fn connect(
+        api: &API,
+        config: &Arc<Configurations>,
+        agent_uuid: &str,
+        label: &str,
+        offer: Sdp,
+    ) -> Result<Peer> {
+        // Prepare the configuration
+        let rtc_config = rtc_configuration(&config.rtc);
+
+        // Create a new RTCPeerConnection
+        let rtc_peer_connection = Arc::new(api.new_peer_connection(rtc_config).await?);
+
+        // Set the handler for Peer connection state
+        let (state_tx, state_rx) = watch::channel(RTCPeerConnectionState::New);
+        rtc_peer_connection.on_peer_connection_state_change(Box::new(
+            move |state: RTCPeerConnectionState| {
+                tracing::info!("rtc_peer_connection.on_peer_connection_state_change({state})");
+                let _ = state_tx.send(state);
+                Box::pin(async {})
+            },
+        ));
+
+        // Create the control data channel, it negotiates the sctp association
+        // every accepted connection opens its own data channel afterwards
+        let label_1 = label.to_string();
+        let label_2 = label_1.clone();
+        let rtc_data_channel = rtc_peer_connection
+            .create_data_channel(
+                label,
+                Some(RTCDataChannelInit {
+                    protocol: Some(CONTROL_PROTOCOL.to_string()),
+                    ..Default::default()
+                }),
+            )
+            .await?;
+
+        rtc_data_channel.on_open(Box::new(move || {
+            tracing::info!("rtc_data_channel.on_open(label: {label_1})");
             Box::pin(async {})
-        },
-    ));
+        }));
+
+        rtc_data_channel.on_close(Box::new(move || {
+            tracing::info!("rtc_data_channel.on_close(label: {label_2})");
+            Box::pin(async {})
+        }));
+
+        rtc_data_channel.on_message(Box::new(move |_msg: DataChannelMessage| {
+            // forward
+            Box::pin(async {})
+        }));
+
+        // Set the handler for ICE connection state
+        rtc_peer_connection.on_ice_connection_state_change(Box::new(
+            |connection_state: webrtc::ice_transport::ice_connection_state::RTCIceConnectionState| {
+                tracing::info!(
+                    "rtc_peer_connection.on_ice_connection_state_change(state: {connection_state})"
+                );
+                Box::pin(async {})
+            },
+        ));
+
+        // Publish local candidates as they are gathered instead of waiting for all of them
+        tracing::info!("session: {}", offer.session);
+        let next_index = Arc::new(AtomicU32::new(0));
+        let restart = Arc::new(AtomicU32::new(offer.restart));
+        let config_c = Arc::clone(config);
+        let agent_uuid_c = agent_uuid.to_string();
+        let session_c = offer.session.clone();
+        let restart_c = Arc::clone(&restart);
+        rtc_peer_connection.on_ice_candidate(Box::new(
+            move |candidate: Option<RTCIceCandidate>| {
+                let config = Arc::clone(&config_c);
+                let agent_uuid = agent_uuid_c.clone();
+                let session = session_c.clone();
+                // indexes keep counting across ice restarts, each candidate has its own key
+                let index = next_index.fetch_add(1, Ordering::SeqCst);
+                let restart = restart_c.load(Ordering::SeqCst);
+                Box::pin(async move {
+                    if let Some(candidate) = to_candidate(session, index, restart, candidate) {
+                        http::publish_client_candidate(&config, &agent_uuid, &candidate).await;
+                    }
+                })
+            },
+        ));
+
+        let mut peer = Peer {
+            rtc_peer_connection,
+            config: Arc::clone(config),
+            agent_uuid: agent_uuid.to_string(),
+            offer,
+            restart,
+            state_rx,
+            remote_candidates: None,
+            traffic: Arc::new(Traffic::default()),
+        };
+        peer.negotiate(None).await?;
 
-    // Create an offer
-    let rtc_session_offer = rtc_peer_connection.create_offer(None).await?;
+        Ok(peer)
+    }
 
-    // Create channel that is blocked until ICE Gathering is complete
-    let mut gather_complete = rtc_peer_connection.gathering_complete_promise().await;
+    // Publish an offer and apply the answer of the agent for the same session and ice generation
+    async fn negotiate(&mut self, options: Option<RTCOfferOptions>) -> Result<()> {
+        // Create an offer
+        let rtc_session_offer = self.rtc_peer_connection.create_offer(options).await?;
 
-    // Sets the LocalDescription, and starts our UDP listeners
-    rtc_peer_connection
-        .set_local_description(rtc_session_offer)
-        .await?;
+        // Sets the LocalDescription, and starts our UDP listeners
+        self.rtc_peer_connection
+            .set_local_description(rtc_session_offer)
+            .await?;
 
-    // Block until ICE Gathering is complete
-    let _ = gather_complete.recv().await;
-
-    // Output the offer
-    if let Some(local_desc) = rtc_peer_connection.local_description().await {
-        let json_str = serde_json::to_string(&local_desc)?;
-        http::publish_client_sdp(
-            config,
-            &agent.uuid,
-            &Sdp {
-                sdp: json_str,
-                is_udp,
-                port: remote_port,
+        // Output the offer
+        if let Some(local_desc) = self.rtc_peer_connection.local_description().await {
+            self.offer.sdp = serde_json::to_vec(&local_desc)?;
+            http::publish_client_sdp(&self.config, &self.agent_uuid, &self.offer).await;
+        }
+
+        // Wait for the answer addressed to this session
+        let agent_sdp = loop {
+            let sdps = http::query_agent_sdp(&self.config, &self.agent_uuid).await;
+            if let Some(s) = sdps
+                .into_iter()
+                .find(|s| s.session == self.offer.session && s.restart == self.offer.restart)
+            {
+                break s;
+            }
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        };
+        let rtc_remote_session_answer =
+            serde_json::from_slice::<RTCSessionDescription>(&agent_sdp.sdp)?;
+
+        // Set the remote SessionDescription
+        self.rtc_peer_connection
+            .set_remote_description(rtc_remote_session_answer)
+            .await?;
+
+        // Add the candidates the agent trickles after its answer
+        if let Some(remote_candidates) = self.remote_candidates.take() {
+            remote_candidates.abort();
+        }
+        let config = Arc::clone(&self.config);
+        let agent_uuid = self.agent_uuid.clone();
+        let session = self.offer.session.clone();
+        self.remote_candidates = Some(tokio::spawn(add_remote_candidates(
+            Arc::clone(&self.rtc_peer_connection),
+            self.offer.session.clone(),
+            self.offer.restart,
+            move || {
+                let config = Arc::clone(&config);
+                let agent_uuid = agent_uuid.clone();
+                let session = session.clone();
+                async move { http::query_agent_candidates(&config, &agent_uuid, &session).await }
             },
-        );
+        )));
+
+        Ok(())
     }
 
-    let mut agent_sdp = Sdp::default();
-    while agent_sdp.sdp.is_empty() {
-        let sdps = http::query_agent_sdp(config, &agent.uuid);
-        for s in &sdps {
-            if s.is_udp == is_udp && s.port == remote_port {
-                agent_sdp = s.to_owned();
-                break;
+    // Restart ice on a failed peer connection, false when no attempt connected it again
+    async fn recover(&mut self) -> bool {
+        for attempt in 1..=MAX_ICE_RESTARTS {
+            tracing::warn!(
+                "session: {} ice restart {attempt}/{MAX_ICE_RESTARTS}",
+                self.offer.session
+            );
+            match tokio::time::timeout(ICE_RESTART_TIMEOUT, self.restart_ice()).await {
+                Ok(Ok(())) => {
+                    tracing::info!("session: {} reconnected", self.offer.session);
+                    return true;
+                }
+                Ok(Err(err)) => tracing::warn!("ice restart error, err: {err}"),
+                Err(_) => tracing::warn!("ice restart timed out after {ICE_RESTART_TIMEOUT:?}"),
             }
         }
-        tokio::time::sleep(Duration::from_secs(1)).await;
+        false
     }
-    let rtc_remote_session_answer = serde_json::from_str::<RTCSessionDescription>(&agent_sdp.sdp)?;
 
-    // Set the remote SessionDescription
-    rtc_peer_connection
-        .set_remote_description(rtc_remote_session_answer)
+    // Renegotiate with fresh ice credentials under the next generation and wait until connected
+    async fn restart_ice(&mut self) -> Result<()> {
+        self.offer.restart += 1;
+        self.restart.store(self.offer.restart, Ordering::SeqCst);
+        self.negotiate(Some(RTCOfferOptions {
+            ice_restart: true,
+            ..Default::default()
+        }))
         .await?;
 
-    let rtc_data_ch_clone = Arc::clone(&rtc_data_channel);
-    let rtc_data_ch_clone_id = rtc_data_ch_clone.id();
-    let rtc_data_ch_clone_label = rtc_data_ch_clone.label().to_string();
-    rtc_data_channel.on_open(Box::new(move || {
+        self.state_rx
+            .wait_for(|state| *state == RTCPeerConnectionState::Connected)
+            .await?;
+        Ok(())
+    }
+
+    async fn close(&mut self, reason: &str) -> Result<()> {
+        if let Some(remote_candidates) = self.remote_candidates.take() {
+            remote_candidates.abort();
+        }
+        self.rtc_peer_connection.close().await?;
         tracing::info!(
-            "rtc_data_ch.on_open(id: {rtc_data_ch_clone_id}), label: {rtc_data_ch_clone_label})"
+            "session: {} closed, reason: {reason}, sent: {} bytes, received: {} bytes",
+            self.offer.session,
+            self.traffic.sent.load(Ordering::SeqCst),
+            self.traffic.received.load(Ordering::SeqCst)
         );
+        Ok(())
+    }
+}
 
-        Box::pin(async move {
-            // tokio start tcp server
-            let tcp_server = tokio::net::TcpListener::bind(format!("127.0.0.1:{}", local_port))
-                .await
-                .unwrap();
+// Resolve once the peer connection failed or was closed
+async fn peer_failed(mut state_rx: watch::Receiver<RTCPeerConnectionState>) {
+    let _ = state_rx
+        .wait_for(|state| {
+            matches!(
+                state,
+                RTCPeerConnectionState::Failed | RTCPeerConnectionState::Closed
+            )
+        })
+        .await;
+}
 
-            // accept tcp connection
-            let (tcp_stream, _) = tcp_server.accept().await.unwrap();
-            tracing::info!("tcp connection accepted");
+// Keep accepting local connections, each one tunneled over a fresh data channel
+async fn serve_tcp(
+    peer: &Peer,
+    tcp_server: &tokio::net::TcpListener,
+    label: &str,
+    done_rx: &mut tokio::sync::mpsc::Receiver<()>,
+) -> Result<Stopped> {
+    let failed = peer_failed(peer.state_rx.clone());
+    tokio::pin!(failed);
+    let mut connections = 0u64;
+    loop {
+        tokio::select! {
+            accepted = tcp_server.accept() => {
+                let (tcp_stream, peer_addr) = accepted?;
+                connections += 1;
+                tracing::info!("tcp connection accepted, peer_addr: {peer_addr}");
+
+                let opened = open_data_channel(
+                    &peer.rtc_peer_connection,
+                    &format!("{label}-{connections}"),
+                    RTCDataChannelInit {
+                        protocol: Some(TCP_PROTOCOL.to_string()),
+                        ..Default::default()
+                    },
+                )
+                .await?;
+                let config = Arc::clone(&peer.config);
+                let traffic = Arc::clone(&peer.traffic);
+                let label = format!("{label}-{connections}");
+                tokio::spawn(async move {
+                    let Ok(raw_data_ch) = opened.await else {
+                        return;
+                    };
+                    tunnel_tcp(tcp_stream, raw_data_ch, &config, &traffic, &label).await;
+                });
+            }
+            _ = &mut failed => {
+                tracing::warn!("peer connection failed");
+                return Ok(Stopped::Failed);
+            }
+            _ = done_rx.recv() => {
+                tracing::info!("received done signal");
+                return Ok(Stopped::Interrupted);
+            }
+        };
+    }
+}
 
-            let raw_data_ch = match rtc_data_ch_clone.detach().await {
-                Ok(raw) => raw,
-                Err(err) => {
-                    tracing::error!("rtc_data_ch_clone.detach() error, err: {err}");
-                    return;
+// Receive local datagrams and tunnel every source address over its own
+// unordered, unreliable data channel, one datagram per message
+async fn serve_udp(
+    peer: &Peer,
+    udp_socket: &Arc<tokio::net::UdpSocket>,
+    label: &str,
+    sources: &mut HashMap<SocketAddr, tokio::sync::mpsc::Sender<Bytes>>,
+    done_rx: &mut tokio::sync::mpsc::Receiver<()>,
+) -> Result<Stopped> {
+    let failed = peer_failed(peer.state_rx.clone());
+    tokio::pin!(failed);
+    let mut buffer = BytesMut::with_capacity(UDP_MESSAGE_SIZE);
+    loop {
+        // takes the allocation back once the datagrams split off it were sent
+        buffer.reserve(UDP_MESSAGE_SIZE);
+        tokio::select! {
+            received = udp_socket.recv_buf_from(&mut buffer) => {
+                let (n, peer_addr) = received?;
+                if n == 0 {
+                    continue;
                 }
-            };
+                let mut datagram = buffer.split_to(n).freeze();
+
+                if let Some(tx) = sources.get(&peer_addr) {
+                    match tx.try_send(datagram) {
+                        // udp may drop, do not stall the other sources
+                        Ok(()) | Err(TrySendError::Full(_)) => continue,
+                        // the data channel of this source closed, open a new one
+                        Err(TrySendError::Closed(d)) => datagram = d,
+                    }
+                }
+
+                // forget the sources whose data channels idled out or failed
+                sources.retain(|_, tx| !tx.is_closed());
+                tracing::info!("udp source {peer_addr} accepted");
+                let tx = open_udp_tunnel(
+                    peer,
+                    &format!("{label}-{peer_addr}"),
+                    udp_socket,
+                    peer_addr,
+                )
+                .await?;
+                let _ = tx.try_send(datagram);
+                sources.insert(peer_addr, tx);
+            }
+            _ = &mut failed => {
+                tracing::warn!("peer connection failed");
+                return Ok(Stopped::Failed);
+            }
+            _ = done_rx.recv() => {
+                tracing::info!("received done signal");
+                return Ok(Stopped::Interrupted);
+            }
+        };
+    }
+}
+
+// Open the data channel of one udp source, datagrams queued before it opens are sent afterwards
+async fn open_udp_tunnel(
+    peer: &Peer,
+    label: &str,
+    udp_socket: &Arc<tokio::net::UdpSocket>,
+    peer_addr: SocketAddr,
+) -> Result<tokio::sync::mpsc::Sender<Bytes>> {
+    let opened = open_data_channel(
+        &peer.rtc_peer_connection,
+        label,
+        RTCDataChannelInit {
+            ordered: Some(false),
+            max_retransmits: Some(0),
+            protocol: Some(UDP_PROTOCOL.to_string()),
+            ..Default::default()
+        },
+    )
+    .await?;
+
+    let (tx, mut rx) = tokio::sync::mpsc::channel::<Bytes>(UDP_QUEUE_SIZE);
+    let udp_socket = Arc::clone(udp_socket);
+    let config = Arc::clone(&peer.config);
+    let traffic = Arc::clone(&peer.traffic);
+    let label = label.to_string();
+    tokio::spawn(async move {
+        let Ok(raw_data_ch) = opened.await else {
+            return;
+        };
+
+        let idle = Idle::default();
+        let reply = async {
+            let mut buffer = BytesMut::zeroed(UDP_MESSAGE_SIZE);
+            // an empty read is the closed data channel, empty datagrams are not tunneled
+            while let Ok((n @ 1.., _)) = raw_data_ch.read_data_channel(&mut buffer).await {
+                idle.touch();
+                if let Err(err) = udp_socket.send_to(&buffer[..n], peer_addr).await {
+                    tracing::error!("udp_socket.send_to({peer_addr}) error, err: {err}");
+                }
+            }
+        };
+
+        // datagrams queue up and get dropped while the data channel is congested
+        let send = async {
+            let backpressure = Backpressure::new(Arc::clone(&raw_data_ch), &config.rtc);
+            while let Some(datagram) = rx.recv().await {
+                idle.touch();
+                backpressure.wait().await;
+                if let Err(err) = raw_data_ch.write(&datagram).await {
+                    tracing::error!("raw_data_ch.write error, err: {err}");
+                    break;
+                }
+            }
+        };
+
+        let reason = tokio::select! {
+            _ = reply => CloseReason::ChannelClosed,
+            // the source was dropped with its session or the write failed
+            _ = send => CloseReason::Finished,
+            _ = idle.expired(UDP_IDLE_TIMEOUT) => CloseReason::Idle,
+        };
+        let _ = raw_data_ch.close().await;
+
+        traffic.add(&raw_data_ch);
+        tracing::info!(
+            "data channel {label} closed, reason: {reason}, sent: {} bytes, received: {} bytes",
+            raw_data_ch.bytes_sent(),
+            raw_data_ch.bytes_received()
+        );
+    });
 
-            let (mut r, mut w) = tokio::io::split(tcp_stream);
-            let raw_data_ch_r = Arc::clone(&raw_data_ch);
-            tokio::spawn(async move {
-                let _ = read_loop(&mut r, raw_data_ch).await;
-            });
+    Ok(tx)
+}
+
+// Create a data channel, the receiver resolves to the detached channel once it is open
+async fn open_data_channel(
+    rtc_peer_connection: &RTCPeerConnection,
+    label: &str,
+    init: RTCDataChannelInit,
+) -> Result<tokio::sync::oneshot::Receiver<Arc<webrtc::data::data_channel::DataChannel>>> {
+    let rtc_data_ch = rtc_peer_connection
+        .create_data_channel(label, Some(init))
+        .await?;
+
+    let rtc_data_ch_label = label.to_string();
+    rtc_data_ch.on_close(Box::new(move || {
+        tracing::info!("rtc_data_ch.on_close(label: {rtc_data_ch_label})");
+        Box::pin(async {})
+    }));
+
+    let (opened_tx, opened_rx) = tokio::sync::oneshot::channel();
+    let rtc_data_ch_clone = Arc::clone(&rtc_data_ch);
+    let rtc_data_ch_label = label.to_string();
+    rtc_data_ch.on_open(Box::new(move || {
+        tracing::info!(
+            "rtc_data_ch.on_open(id: {}, label: {rtc_data_ch_label})",
+            rtc_data_ch_clone.id()
+        );
 
-            tokio::spawn(async move {
-                let _ = write_loop(&mut w, raw_data_ch_r).await;
-            });
+        Box::pin(async move {
+            match rtc_data_ch_clone.detach().await {
+                Ok(raw) => {
+                    let _ = opened_tx.send(raw);
+                }
+                Err(err) => {
+                    tracing::error!("rtc_data_ch_clone.detach() error, err: {err}");
+                }
+            }
         })
     }));
 
-    tokio::select! {
-        _ = done_rx.recv() => {
-            tracing::info!("received done signal");
+    Ok(opened_rx)
+}
+
+// Pump a local connection through its data channel, eof travels as fin either way
+// and anything else resets both sides
+async fn tunnel_tcp(
+    tcp_stream: tokio::net::TcpStream,
+    raw_data_ch: Arc<webrtc::data::data_channel::DataChannel>,
+    config: &Configurations,
+    traffic: &Traffic,
+    label: &str,
+) {
+    let (mut r, mut w) = tcp_stream.into_split();
+    let reason = match tokio::try_join!(
+        read_loop(&mut r, &raw_data_ch, config),
+        write_loop(&mut w, &raw_data_ch),
+    ) {
+        Ok(_) => CloseReason::Finished,
+        Err(reason) => {
+            // the connection is reset once both halves are dropped
+            if let Err(err) = socket2::SockRef::from(w.as_ref()).set_linger(Some(Duration::ZERO)) {
+                tracing::warn!("set_linger error, err: {err}");
+            }
+            reason
         }
     };
+    let _ = raw_data_ch.close().await;
 
-    rtc_peer_connection.close().await?;
-
-    Ok(())
+    traffic.add(&raw_data_ch);
+    tracing::info!(
+        "data channel {label} closed, reason: {reason}, sent: {} bytes, received: {} bytes",
+        raw_data_ch.bytes_sent(),
+        raw_data_ch.bytes_received()
+    );
 }
 
+// Forward what the local connection reads to the data channel, exactly the bytes read in
+// messages of at most the message size, and send fin on eof
 async fn read_loop(
-    socket_r: &mut tokio::io::ReadHalf<tokio::net::TcpStream>,
-    data_ch_w: Arc<webrtc::data::data_channel::DataChannel>,
-) -> Result<()> {
-    let mut buffer = vec![0u8; MESSAGE_SIZE];
-    let mut result = Result::<usize>::Ok(0);
-    while result.is_ok() {
-        let n = match socket_r.read(&mut buffer).await {
-            Ok(n) => {
-                result = data_ch_w
-                    .write(&Bytes::from(buffer[..n].to_vec()))
-                    .await
-                    .map_err(Into::into);
-                n
-            }
-            Err(err) => {
-                tracing::error!("data_ch_w.write error, err: {err}");
-                return Ok(());
-            }
-        };
+    socket_r: &mut tokio::net::tcp::OwnedReadHalf,
+    data_ch_w: &Arc<webrtc::data::data_channel::DataChannel>,
+    config: &Configurations,
+) -> Result<(), CloseReason> {
+    let backpressure = Backpressure::new(Arc::clone(data_ch_w), &config.rtc);
+    let size = config.rtc.max_message_size;
+    let mut buffer = BytesMut::with_capacity(size);
+    loop {
+        backpressure.wait().await;
+        // takes the allocation back once the messages split off it were sent
+        buffer.reserve(size);
+        let n = socket_r.read_buf(&mut buffer).await?;
+        if n == 0 {
+            // an empty string message is the fin, data is always binary
+            data_ch_w.write_data_channel(&Bytes::new(), true).await?;
+            return Ok(());
+        }
+        let mut data = buffer.split_to(n).freeze();
+        while !data.is_empty() {
+            let message = data.split_to(data.len().min(size));
+            data_ch_w.write(&message).await?;
+        }
     }
-
-    Ok(())
 }
 
+// Forward data channel messages to the local connection until the agent sent fin
 async fn write_loop(
-    socket_w: &mut tokio::io::WriteHalf<tokio::net::TcpStream>,
-    data_ch_r: Arc<webrtc::data::data_channel::DataChannel>,
-) -> Result<()> {
-    let mut buffer = vec![0u8; MESSAGE_SIZE];
+    socket_w: &mut tokio::net::tcp::OwnedWriteHalf,
+    data_ch_r: &webrtc::data::data_channel::DataChannel,
+) -> Result<(), CloseReason> {
+    // one buffer for the whole channel, a message must fit in it at once
+    let mut buffer = BytesMut::zeroed(MAX_MESSAGE_SIZE);
     loop {
-        let n = match data_ch_r.read(&mut buffer).await {
-            Ok(n) => {
-                socket_w.write(&buffer[..n]).await?;
-                n
-            }
-            Err(err) => {
-                tracing::error!("socket_w.write error, err: {err}");
+        match data_ch_r.read_data_channel(&mut buffer).await {
+            Ok((0, true)) => {
+                socket_w.shutdown().await?;
                 return Ok(());
             }
-        };
+            Ok((0, false)) => return Err(CloseReason::ChannelClosed),
+            Ok((n, _)) => socket_w.write_all(&buffer[..n]).await?,
+            // a short buffer error means the agent sends messages larger than MAX_MESSAGE_SIZE
+            Err(err) => return Err(err.into()),
+        }
     }
 }
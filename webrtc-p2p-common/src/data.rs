@@ -0,0 +1,249 @@
+use std::net::IpAddr;
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+
+use crate::tunnel::MAX_MESSAGE_SIZE;
+
+static DEFAULT_STUN_SERVER_URL: &str = "stun:stun.l.google.com:19302";
+static DEFAULT_MAX_MESSAGE_SIZE: usize = 64 * 1024;
+static DEFAULT_BUFFERED_AMOUNT_HIGH: usize = 1024 * 1024;
+static DEFAULT_BUFFERED_AMOUNT_LOW: usize = 256 * 1024;
+
+// Peer connection and tunnel options, flattened into the agent and client configurations
+#[derive(Debug, Default, Serialize, Deserialize)]
+pub struct RtcConfigurations {
+    // stun and turn servers, turn servers with their credentials
+    #[serde(default)]
+    pub ice_servers: Vec<IceServer>,
+    // relay forces every connection through a turn server, e.g. to debug one
+    #[serde(default)]
+    pub ice_transport_policy: IceTransportPolicy,
+    // superseded by ice_servers, moved there when loading
+    #[serde(default, skip_serializing)]
+    stun_server_urls: Vec<String>,
+
+    // local udp port range candidates are gathered on, 0 for any ephemeral port
+    #[serde(default)]
+    pub udp_port_min: u16,
+    #[serde(default)]
+    pub udp_port_max: u16,
+    // network interfaces never gathered on, a trailing * matches a prefix, e.g. docker0 or veth*
+    #[serde(default)]
+    pub excluded_interfaces: Vec<String>,
+    #[serde(default)]
+    pub excluded_ips: Vec<IpAddr>,
+    // public ips of a 1:1 nat, e.g. the elastic ip of a cloud vm
+    #[serde(default)]
+    pub nat_1to1_ips: Vec<String>,
+    // host replaces the private ips, srflx adds candidates and needs no stun server
+    #[serde(default)]
+    pub nat_1to1_candidate_type: Nat1To1CandidateType,
+    // hide local ips behind mdns names in host candidates
+    #[serde(default)]
+    pub mdns_host_candidates: bool,
+    // gather tcp candidates besides udp ones, webrtc-rs 0.12 has no ice-tcp yet and the
+    // agent and client refuse to start while this is true
+    #[serde(default)]
+    pub ice_tcp: bool,
+
+    // largest data channel message sent over a tcp tunnel, at most 64 KiB
+    #[serde(default)]
+    pub max_message_size: usize,
+    // stop reading a local socket while more bytes than this wait on its data channel
+    #[serde(default)]
+    pub buffered_amount_high: usize,
+    // and resume once they drained to this
+    #[serde(default)]
+    pub buffered_amount_low: usize,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct IceServer {
+    pub urls: Vec<String>,
+    // turn credentials, empty for stun servers
+    #[serde(default)]
+    pub username: String,
+    #[serde(default)]
+    pub credential: String,
+    #[serde(default)]
+    pub credential_type: IceCredentialType,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IceCredentialType {
+    #[default]
+    Password,
+    // not supported by webrtc, servers using it are skipped
+    Oauth,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Nat1To1CandidateType {
+    #[default]
+    Host,
+    Srflx,
+}
+
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum IceTransportPolicy {
+    #[default]
+    All,
+    Relay,
+}
+
+impl RtcConfigurations {
+    // Fill in the defaults and fix invalid values, true when the file has to be rewritten
+    pub fn load_defaults(&mut self) -> bool {
+        let mut update = false;
+        if self.ice_servers.is_empty() {
+            let urls = if self.stun_server_urls.is_empty() {
+                vec![DEFAULT_STUN_SERVER_URL.to_owned()]
+            } else {
+                std::mem::take(&mut self.stun_server_urls)
+            };
+            self.ice_servers = vec![IceServer {
+                urls,
+                ..Default::default()
+            }];
+            update = true;
+        }
+        if self.max_message_size == 0 {
+            self.max_message_size = DEFAULT_MAX_MESSAGE_SIZE;
+            update = true;
+        }
+        if self.max_message_size > MAX_MESSAGE_SIZE {
+            tracing::warn!("config.max_message_size > {MAX_MESSAGE_SIZE}");
+            self.max_message_size = MAX_MESSAGE_SIZE;
+        }
+        if self.buffered_amount_high == 0 {
+            self.buffered_amount_high = DEFAULT_BUFFERED_AMOUNT_HIGH;
+            update = true;
+        }
+        if self.buffered_amount_low == 0 {
+            self.buffered_amount_low = DEFAULT_BUFFERED_AMOUNT_LOW;
+            update = true;
+        }
+        if self.buffered_amount_low > self.buffered_amount_high {
+            tracing::warn!("config.buffered_amount_low > config.buffered_amount_high");
+            self.buffered_amount_low = self.buffered_amount_high;
+        }
+        if (self.udp_port_min == 0) != (self.udp_port_max == 0)
+            || self.udp_port_min > self.udp_port_max
+        {
+            tracing::error!(
+                "config.udp_port_min {} and config.udp_port_max {} are not a port range, any port is used",
+                self.udp_port_min,
+                self.udp_port_max
+            );
+            self.udp_port_min = 0;
+            self.udp_port_max = 0;
+        }
+        update
+    }
+
+    // Options that can not work, the agent and client stop instead of ignoring them
+    pub fn check(&self) -> Result<()> {
+        if self.ice_tcp {
+            return Err(anyhow!(
+                "invalid config, ice_tcp is not supported by webrtc-rs 0.12, set it to false"
+            ));
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn stun_server_urls_move_to_ice_servers() {
+        let mut config = serde_json::from_str::<RtcConfigurations>(
+            r#"{"stun_server_urls": ["stun:a.example:3478", "stun:b.example:3478"]}"#,
+        )
+        .unwrap();
+        assert!(config.load_defaults());
+        assert_eq!(config.ice_servers.len(), 1);
+        assert_eq!(
+            config.ice_servers[0].urls,
+            ["stun:a.example:3478", "stun:b.example:3478"]
+        );
+        assert!(config.stun_server_urls.is_empty());
+
+        // the superseded key is not written back
+        let json = serde_json::to_value(&config).unwrap();
+        assert!(json.get("stun_server_urls").is_none());
+    }
+
+    #[test]
+    fn ice_servers_win_over_stun_server_urls() {
+        let mut config = serde_json::from_str::<RtcConfigurations>(
+            r#"{"ice_servers": [{"urls": ["turn:t.example:3478"], "username": "u", "credential": "c"}],
+                "stun_server_urls": ["stun:a.example:3478"]}"#,
+        )
+        .unwrap();
+        config.load_defaults();
+        assert_eq!(config.ice_servers.len(), 1);
+        assert_eq!(config.ice_servers[0].urls, ["turn:t.example:3478"]);
+    }
+
+    #[test]
+    fn ice_servers_default_to_a_public_stun_server() {
+        let mut config = RtcConfigurations::default();
+        assert!(config.load_defaults());
+        assert_eq!(config.ice_servers[0].urls, [DEFAULT_STUN_SERVER_URL]);
+    }
+
+    #[test]
+    fn udp_port_range_needs_both_bounds_in_order() {
+        for (min, max, range) in [
+            (0, 0, (0, 0)),
+            (50000, 50100, (50000, 50100)),
+            (50000, 50000, (50000, 50000)),
+            (50000, 0, (0, 0)),
+            (0, 50100, (0, 0)),
+            (50100, 50000, (0, 0)),
+        ] {
+            let mut config = RtcConfigurations {
+                udp_port_min: min,
+                udp_port_max: max,
+                ..Default::default()
+            };
+            config.load_defaults();
+            assert_eq!((config.udp_port_min, config.udp_port_max), range);
+        }
+    }
+
+    #[test]
+    fn ice_tcp_fails_the_check_and_is_kept() {
+        let mut config = serde_json::from_str::<RtcConfigurations>(r#"{"ice_tcp": true}"#).unwrap();
+        config.load_defaults();
+        assert!(config.check().is_err());
+        let json = serde_json::to_value(&config).unwrap();
+        assert_eq!(json["ice_tcp"], true);
+
+        config.ice_tcp = false;
+        assert!(config.check().is_ok());
+    }
+
+    #[test]
+    fn max_message_size_is_clamped() {
+        let mut config = RtcConfigurations {
+            max_message_size: 256 * 1024,
+            ..Default::default()
+        };
+        config.load_defaults();
+        assert_eq!(config.max_message_size, MAX_MESSAGE_SIZE);
+
+        let mut config = RtcConfigurations {
+            max_message_size: 16 * 1024,
+            ..Default::default()
+        };
+        config.load_defaults();
+        assert_eq!(config.max_message_size, 16 * 1024);
+    }
+}
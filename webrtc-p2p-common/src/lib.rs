@@ -0,0 +1,5 @@
+pub mod data;
+pub mod rtc;
+pub mod tunnel;
+
+pub use data::*;
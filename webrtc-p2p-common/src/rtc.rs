@@ -0,0 +1,279 @@
+use std::collections::HashSet;
+use std::future::Future;
+use std::net::IpAddr;
+use std::sync::Arc;
+use std::time::Duration;
+
+use anyhow::Result;
+use webrtc::api::setting_engine::SettingEngine;
+use webrtc::ice::mdns::MulticastDnsMode;
+use webrtc::ice::udp_network::{EphemeralUDP, UDPNetwork};
+use webrtc::ice_transport::ice_candidate::{RTCIceCandidate, RTCIceCandidateInit};
+use webrtc::ice_transport::ice_candidate_type::RTCIceCandidateType;
+use webrtc::ice_transport::ice_server::RTCIceServer;
+use webrtc::peer_connection::configuration::RTCConfiguration;
+use webrtc::peer_connection::policy::ice_transport_policy::RTCIceTransportPolicy;
+use webrtc::peer_connection::RTCPeerConnection;
+
+use signal_protocol::Candidate;
+
+use crate::data::{IceCredentialType, IceTransportPolicy, Nat1To1CandidateType, RtcConfigurations};
+
+const CANDIDATE_POLL_INTERVAL: Duration = Duration::from_millis(500);
+// stop waiting for more remote candidates, the connection lives on with the ones added
+const CANDIDATE_POLL_TIMEOUT: Duration = Duration::from_secs(30);
+
+// Detached data channels and the candidate gathering options of the configuration
+pub fn setting_engine(config: &RtcConfigurations) -> Result<SettingEngine> {
+    let mut s = SettingEngine::default();
+    s.detach_data_channels();
+
+    if config.udp_port_min != 0 || config.udp_port_max != 0 {
+        s.set_udp_network(UDPNetwork::Ephemeral(EphemeralUDP::new(
+            config.udp_port_min,
+            config.udp_port_max,
+        )?));
+    }
+
+    if !config.excluded_interfaces.is_empty() {
+        let excluded_interfaces = config.excluded_interfaces.clone();
+        s.set_interface_filter(Box::new(move |name: &str| {
+            !interface_excluded(&excluded_interfaces, name)
+        }));
+    }
+    if !config.excluded_ips.is_empty() {
+        let excluded_ips = config.excluded_ips.clone();
+        s.set_ip_filter(Box::new(move |ip: IpAddr| !excluded_ips.contains(&ip)));
+    }
+
+    if !config.nat_1to1_ips.is_empty() {
+        s.set_nat_1to1_ips(
+            config.nat_1to1_ips.clone(),
+            match config.nat_1to1_candidate_type {
+                Nat1To1CandidateType::Host => RTCIceCandidateType::Host,
+                Nat1To1CandidateType::Srflx => RTCIceCandidateType::Srflx,
+            },
+        );
+    }
+
+    if config.mdns_host_candidates {
+        s.set_ice_multicast_dns_mode(MulticastDnsMode::QueryAndGather);
+    }
+
+    Ok(s)
+}
+
+// Whether a network interface matches one of the patterns, a trailing * matches a prefix
+fn interface_excluded(patterns: &[String], name: &str) -> bool {
+    patterns
+        .iter()
+        .any(|pattern| match pattern.strip_suffix('*') {
+            Some(prefix) => name.starts_with(prefix),
+            None => name == pattern,
+        })
+}
+
+// Ice servers and transport policy of a new peer connection
+pub fn rtc_configuration(config: &RtcConfigurations) -> RTCConfiguration {
+    let ice_servers = config
+        .ice_servers
+        .iter()
+        .filter(|ice_server| {
+            if ice_server.credential_type == IceCredentialType::Oauth {
+                tracing::warn!(
+                    "oauth credential is not supported, skip {:?}",
+                    ice_server.urls
+                );
+                return false;
+            }
+            true
+        })
+        .map(|ice_server| RTCIceServer {
+            urls: ice_server.urls.clone(),
+            username: ice_server.username.clone(),
+            credential: ice_server.credential.clone(),
+        })
+        .collect();
+
+    RTCConfiguration {
+        ice_servers,
+        ice_transport_policy: match config.ice_transport_policy {
+            IceTransportPolicy::All => RTCIceTransportPolicy::All,
+            IceTransportPolicy::Relay => RTCIceTransportPolicy::Relay,
+        },
+        ..Default::default()
+    }
+}
+
+// Turn a gathered candidate into its signalling form, None from webrtc means gathering is complete
+pub fn to_candidate(
+    session: String,
+    index: u32,
+    restart: u32,
+    candidate: Option<RTCIceCandidate>,
+) -> Option<Candidate> {
+    let Some(candidate) = candidate else {
+        return Some(Candidate {
+            session,
+            index,
+            complete: true,
+            restart,
+            ..Default::default()
+        });
+    };
+
+    match candidate.to_json().map(|init| serde_json::to_vec(&init)) {
+        Ok(Ok(candidate)) => Some(Candidate {
+            session,
+            index,
+            candidate,
+            complete: false,
+            restart,
+        }),
+        Ok(Err(err)) => {
+            tracing::warn!("serialize candidate error, err: {err}");
+            None
+        }
+        Err(err) => {
+            tracing::warn!("candidate.to_json() error, err: {err}");
+            None
+        }
+    }
+}
+
+// Poll the remote candidates of a session and ice generation with query and add the new ones
+// until the peer reports complete
+pub async fn add_remote_candidates<F, Fut>(
+    rtc_peer_conn: Arc<RTCPeerConnection>,
+    session: String,
+    restart: u32,
+    query: F,
+) where
+    F: Fn() -> Fut,
+    Fut: Future<Output = Vec<Candidate>>,
+{
+    // peers without sessions do not trickle
+    if session.is_empty() {
+        return;
+    }
+
+    let mut added = HashSet::new();
+    let started = std::time::Instant::now();
+    while started.elapsed() < CANDIDATE_POLL_TIMEOUT {
+        let mut complete = false;
+        for candidate in query().await {
+            if candidate.restart != restart || !added.insert(candidate.index) {
+                continue;
+            }
+            if candidate.complete {
+                complete = true;
+                continue;
+            }
+            match serde_json::from_slice::<RTCIceCandidateInit>(&candidate.candidate) {
+                Ok(init) => {
+                    if let Err(err) = rtc_peer_conn.add_ice_candidate(init).await {
+                        tracing::warn!("rtc_peer_conn.add_ice_candidate error, err: {err}");
+                    }
+                }
+                Err(err) => tracing::warn!("malformed candidate {}, err: {err}", candidate.index),
+            }
+        }
+        if complete {
+            tracing::info!("session: {session} received {} candidates", added.len() - 1);
+            return;
+        }
+        tokio::time::sleep(CANDIDATE_POLL_INTERVAL).await;
+    }
+    tracing::warn!("session: {session} candidates incomplete after {CANDIDATE_POLL_TIMEOUT:?}");
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::data::IceServer;
+
+    #[test]
+    fn interface_excluded_matches_names_and_prefixes() {
+        let patterns = ["docker0".to_string(), "veth*".to_string()];
+        assert!(interface_excluded(&patterns, "docker0"));
+        assert!(interface_excluded(&patterns, "veth"));
+        assert!(interface_excluded(&patterns, "veth1a2b"));
+        assert!(!interface_excluded(&patterns, "docker01"));
+        assert!(!interface_excluded(&patterns, "eth0"));
+        assert!(!interface_excluded(&patterns, "myveth0"));
+        // a lone * excludes every interface
+        assert!(interface_excluded(&["*".to_string()], "eth0"));
+        assert!(!interface_excluded(&[], "eth0"));
+    }
+
+    #[test]
+    fn rtc_configuration_skips_oauth_servers() {
+        let mut config = RtcConfigurations::default();
+        config.ice_servers = vec![
+            IceServer {
+                urls: vec!["stun:a.example:3478".to_string()],
+                ..Default::default()
+            },
+            IceServer {
+                urls: vec!["turn:oauth.example:3478".to_string()],
+                username: "u".to_string(),
+                credential: "token".to_string(),
+                credential_type: IceCredentialType::Oauth,
+            },
+            IceServer {
+                urls: vec!["turn:t.example:3478".to_string()],
+                username: "u".to_string(),
+                credential: "c".to_string(),
+                credential_type: IceCredentialType::Password,
+            },
+        ];
+        config.ice_transport_policy = IceTransportPolicy::Relay;
+        let rtc_config = rtc_configuration(&config);
+        let urls = rtc_config
+            .ice_servers
+            .iter()
+            .map(|ice_server| ice_server.urls[0].as_str())
+            .collect::<Vec<_>>();
+        assert_eq!(urls, ["stun:a.example:3478", "turn:t.example:3478"]);
+        assert_eq!(rtc_config.ice_servers[1].username, "u");
+        assert_eq!(rtc_config.ice_servers[1].credential, "c");
+        assert_eq!(
+            rtc_config.ice_transport_policy,
+            RTCIceTransportPolicy::Relay
+        );
+    }
+
+    #[test]
+    fn to_candidate_marks_the_end_of_gathering() {
+        let candidate = to_candidate("s1".to_string(), 4, 2, None).unwrap();
+        assert_eq!(
+            candidate,
+            Candidate {
+                session: "s1".to_string(),
+                index: 4,
+                complete: true,
+                restart: 2,
+                ..Default::default()
+            }
+        );
+    }
+
+    #[test]
+    fn to_candidate_carries_the_candidate_init() {
+        let gathered = RTCIceCandidate {
+            foundation: "1".to_string(),
+            priority: 2130706431,
+            address: "192.168.1.2".to_string(),
+            protocol: webrtc::ice_transport::ice_protocol::RTCIceProtocol::Udp,
+            port: 50000,
+            typ: RTCIceCandidateType::Host,
+            component: 1,
+            ..Default::default()
+        };
+        let candidate = to_candidate("s1".to_string(), 0, 0, Some(gathered)).unwrap();
+        assert!(!candidate.complete);
+
+        let init = serde_json::from_slice::<RTCIceCandidateInit>(&candidate.candidate).unwrap();
+        assert!(init.candidate.contains("192.168.1.2 50000 typ host"));
+    }
+}
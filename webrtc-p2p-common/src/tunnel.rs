@@ -0,0 +1,147 @@
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use tokio::sync::Notify;
+use tokio::time::Instant;
+use webrtc::data::data_channel::DataChannel;
+
+use crate::data::RtcConfigurations;
+
+// largest message webrtc reassembles, every receive buffer is this large so a tcp tunnel
+// works whatever max_message_size the peer sends with
+pub const MAX_MESSAGE_SIZE: usize = 64 * 1024;
+// largest udp payload, every datagram travels as one data channel message
+pub const UDP_MESSAGE_SIZE: usize = 65535;
+// udp has no close, a data channel quiet this long in both directions is closed like a nat
+// mapping would expire, the client opens a new one for the next datagram of its source
+pub const UDP_IDLE_TIMEOUT: Duration = Duration::from_secs(120);
+
+// Pauses a pump while more than the high watermark waits on its data channel,
+// until the queue drained to the low watermark
+pub struct Backpressure {
+    data_ch: Arc<DataChannel>,
+    high: usize,
+    drained: Arc<Notify>,
+}
+
+impl Backpressure {
+    pub fn new(data_ch: Arc<DataChannel>, config: &RtcConfigurations) -> Self {
+        let drained = Arc::new(Notify::new());
+        let drained_c = Arc::clone(&drained);
+        data_ch.set_buffered_amount_low_threshold(config.buffered_amount_low);
+        data_ch.on_buffered_amount_low(Box::new(move || {
+            drained_c.notify_one();
+            Box::pin(async {})
+        }));
+        Self {
+            data_ch,
+            high: config.buffered_amount_high,
+            drained,
+        }
+    }
+
+    pub async fn wait(&self) {
+        while self.data_ch.buffered_amount() > self.high {
+            self.drained.notified().await;
+        }
+    }
+}
+
+// Time since the last message crossed a tunnel in either direction
+pub struct Idle {
+    last: std::sync::Mutex<Instant>,
+}
+
+impl Default for Idle {
+    fn default() -> Self {
+        Self {
+            last: std::sync::Mutex::new(Instant::now()),
+        }
+    }
+}
+
+impl Idle {
+    pub fn touch(&self) {
+        *self.last.lock().unwrap() = Instant::now();
+    }
+
+    // Resolve once nothing was touched for timeout
+    pub async fn expired(&self, timeout: Duration) {
+        loop {
+            let deadline = *self.last.lock().unwrap() + timeout;
+            if deadline <= Instant::now() {
+                return;
+            }
+            tokio::time::sleep_until(deadline).await;
+        }
+    }
+}
+
+// Bytes tunneled over the data channels of a session
+#[derive(Default)]
+pub struct Traffic {
+    pub sent: AtomicU64,
+    pub received: AtomicU64,
+}
+
+impl Traffic {
+    pub fn add(&self, data_ch: &DataChannel) {
+        self.sent
+            .fetch_add(data_ch.bytes_sent() as u64, Ordering::SeqCst);
+        self.received
+            .fetch_add(data_ch.bytes_received() as u64, Ordering::SeqCst);
+    }
+}
+
+// Why a tunnel data channel closed
+pub enum CloseReason {
+    // both sides sent fin after all their data
+    Finished,
+    // the data channel closed without a fin, e.g. the peer connection went away
+    ChannelClosed,
+    // no datagram crossed a udp tunnel for UDP_IDLE_TIMEOUT
+    Idle,
+    Channel(webrtc::data::Error),
+    Socket(std::io::Error),
+}
+
+impl From<webrtc::data::Error> for CloseReason {
+    fn from(err: webrtc::data::Error) -> Self {
+        CloseReason::Channel(err)
+    }
+}
+
+impl From<std::io::Error> for CloseReason {
+    fn from(err: std::io::Error) -> Self {
+        CloseReason::Socket(err)
+    }
+}
+
+impl std::fmt::Display for CloseReason {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self {
+            CloseReason::Finished => write!(f, "finished"),
+            CloseReason::ChannelClosed => write!(f, "data channel closed"),
+            CloseReason::Idle => write!(f, "idle for {UDP_IDLE_TIMEOUT:?}"),
+            CloseReason::Channel(err) => write!(f, "data channel error: {err}"),
+            CloseReason::Socket(err) => write!(f, "socket error: {err}"),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[tokio::test]
+    async fn idle_expires_only_after_the_last_touch() {
+        let idle = Idle::default();
+        let started = Instant::now();
+        tokio::join!(idle.expired(Duration::from_millis(100)), async {
+            tokio::time::sleep(Duration::from_millis(60)).await;
+            idle.touch();
+        });
+        assert!(started.elapsed() >= Duration::from_millis(160));
+    }
+}